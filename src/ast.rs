@@ -1,4 +1,6 @@
 use combine::stream::position::SourcePosition;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Prog {
@@ -25,8 +27,22 @@ pub struct App {
     pub range: SourceRange,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct SourceRange {
     pub start: SourcePosition,
     pub end: SourcePosition,
 }
+
+// SourcePosition が Hash を実装していないので、行と列から計算する
+impl Hash for SourceRange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.start.line, self.start.column).hash(state);
+        (self.end.line, self.end.column).hash(state);
+    }
+}
+
+impl fmt::Display for SourceRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start.line, self.start.column)
+    }
+}
@@ -0,0 +1,321 @@
+use crate::ir::{Env, Instr, Native, Value};
+use crate::vm::{RuntimeError, VM};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+// λx y. x / λx y. y
+pub fn boolean(b: bool) -> Value {
+    if b {
+        // 引数 x を返すため、環境に恒等関数を置いて x に適用する
+        binary(VecDeque::from(vec![app(3, 2)]), Env::nil().push(identity()))
+    } else {
+        binary(VecDeque::new(), Env::nil())
+    }
+}
+
+// λf x. f (f (... (f x)))
+pub fn numeral(n: usize) -> Value {
+    // k 回目の適用の時点で f は k+1 番目にある
+    let body = (1..=n).map(|k| app(k + 1, 1)).collect();
+    binary(body, Env::nil())
+}
+
+// λs. s a b
+pub fn pair(a: Value, b: Value) -> Value {
+    let code = VecDeque::from(vec![app(1, 3), app(1, 3)]);
+    Value::Closure {
+        code,
+        env: Env::nil().push(a).push(b),
+        origin: None,
+    }
+}
+
+// 右畳み込みによるリスト λc n. c x1 (c x2 (... (c xk n)))
+pub fn list(items: impl IntoIterator<Item = Value>) -> Value {
+    let mut env = Env::nil();
+    let mut len = 0;
+    for item in items {
+        env = env.push(item);
+        len += 1;
+    }
+    // 末尾の要素から順に畳み込む。j 回目は c が 2j+2 番目、要素が 3j+3 番目にある
+    let body = (0..len)
+        .flat_map(|j| [app(2 * j + 2, 3 * j + 3), app(1, 2)])
+        .collect();
+    binary(body, env)
+}
+
+pub fn bytes(bytes: &[u8]) -> Value {
+    list(bytes.iter().map(|&byte| Value::Char(byte.into())))
+}
+
+// ========================================================================== //
+
+// 目印を渡して適用し、返ってきた目印で真偽を見分ける
+pub fn decode_bool(vm: &mut VM, value: &Value) -> Result<Option<bool>, RuntimeError> {
+    let touched = Rc::new(Cell::new(false));
+    let (t, f) = (marker(&touched), marker(&touched));
+    let Some(partial) = probe(vm, value.clone(), t.clone())? else {
+        return Ok(None);
+    };
+    let Some(result) = probe(vm, partial, f.clone())? else {
+        return Ok(None);
+    };
+    if touched.get() {
+        return Ok(None);
+    }
+    Ok(if same(&result, &t) {
+        Some(true)
+    } else if same(&result, &f) {
+        Some(false)
+    } else {
+        None
+    })
+}
+
+// 直前の結果にだけ適用される後者関数を渡し、適用された回数を数える
+pub fn decode_numeral(vm: &mut VM, value: &Value) -> Result<Option<usize>, RuntimeError> {
+    let touched = Rc::new(Cell::new(false));
+    let chain = Rc::new(RefCell::new(Chain {
+        last: marker(&touched),
+        count: 0,
+    }));
+    let zero = chain.borrow().last.clone();
+
+    let succ = {
+        let chain = chain.clone();
+        let touched = touched.clone();
+        Value::Native(Native::new(move |_, arg| {
+            let mut chain = chain.borrow_mut();
+            if !same(&arg, &chain.last) {
+                touched.set(true);
+            }
+            chain.last = marker(&touched);
+            chain.count += 1;
+            Ok(chain.last.clone())
+        }))
+    };
+
+    let Some(partial) = probe(vm, value.clone(), succ)? else {
+        return Ok(None);
+    };
+    let Some(result) = probe(vm, partial, zero)? else {
+        return Ok(None);
+    };
+    let chain = chain.borrow();
+    if touched.get() || !same(&result, &chain.last) {
+        return Ok(None);
+    }
+    Ok(Some(chain.count))
+}
+
+// 2引数を受け取って控えておく選択関数を渡す
+pub fn decode_pair(vm: &mut VM, value: &Value) -> Result<Option<(Value, Value)>, RuntimeError> {
+    let touched = Rc::new(Cell::new(false));
+    let done = marker(&touched);
+    let received = Rc::new(RefCell::new(Vec::new()));
+
+    let select = {
+        let received = received.clone();
+        let done = done.clone();
+        Value::Native(Native::new(move |_, a| {
+            let received = received.clone();
+            let done = done.clone();
+            Ok(Value::Native(Native::new(move |_, b| {
+                received.borrow_mut().push((a.clone(), b));
+                Ok(done.clone())
+            })))
+        }))
+    };
+
+    let Some(result) = probe(vm, value.clone(), select)? else {
+        return Ok(None);
+    };
+    let mut received = received.take();
+    if touched.get() || !same(&result, &done) || received.len() != 1 {
+        return Ok(None);
+    }
+    Ok(received.pop())
+}
+
+// 直前の結果にだけ適用される cons を渡し、適用された順に要素を集める
+pub fn decode_list(vm: &mut VM, value: &Value) -> Result<Option<Vec<Value>>, RuntimeError> {
+    let touched = Rc::new(Cell::new(false));
+    let chain = Rc::new(RefCell::new(Chain {
+        last: marker(&touched),
+        count: 0,
+    }));
+    let nil = chain.borrow().last.clone();
+    let items = Rc::new(RefCell::new(Vec::new()));
+
+    let cons = {
+        let chain = chain.clone();
+        let touched = touched.clone();
+        let items = items.clone();
+        Value::Native(Native::new(move |_, item| {
+            let chain = chain.clone();
+            let touched = touched.clone();
+            let items = items.clone();
+            Ok(Value::Native(Native::new(move |_, acc| {
+                let mut chain = chain.borrow_mut();
+                if !same(&acc, &chain.last) {
+                    touched.set(true);
+                }
+                items.borrow_mut().push(item.clone());
+                chain.last = marker(&touched);
+                chain.count += 1;
+                Ok(chain.last.clone())
+            })))
+        }))
+    };
+
+    let Some(partial) = probe(vm, value.clone(), cons)? else {
+        return Ok(None);
+    };
+    let Some(result) = probe(vm, partial, nil)? else {
+        return Ok(None);
+    };
+    if touched.get() || !same(&result, &chain.borrow().last) {
+        return Ok(None);
+    }
+    // 末尾の要素から畳み込まれるので逆順に集まっている
+    let mut items = items.take();
+    items.reverse();
+    Ok(Some(items))
+}
+
+pub fn decode_bytes(vm: &mut VM, value: &Value) -> Result<Option<Vec<u8>>, RuntimeError> {
+    let Some(items) = decode_list(vm, value)? else {
+        return Ok(None);
+    };
+    Ok(items
+        .iter()
+        .map(|item| match item {
+            Value::Char(c) => u8::try_from(*c).ok(),
+            _ => None,
+        })
+        .collect())
+}
+
+// ========================================================================== //
+
+struct Chain {
+    last: Value,
+    count: usize,
+}
+
+// 型の合わない適用で止まった場合は、その符号化ではなかったものとして扱う
+fn probe(vm: &mut VM, func: Value, arg: Value) -> Result<Option<Value>, RuntimeError> {
+    match vm.apply(func, arg) {
+        Ok(value) => Ok(Some(value)),
+        Err(RuntimeError::NotAChar { .. }) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// 関数として呼ばれたら印を付ける目印。ポインタの一致で見分ける
+fn marker(touched: &Rc<Cell<bool>>) -> Value {
+    let touched = touched.clone();
+    Value::Native(Native::new(move |_, arg| {
+        touched.set(true);
+        Ok(arg)
+    }))
+}
+
+fn same(a: &Value, b: &Value) -> bool {
+    matches!((a, b), (Value::Native(a), Value::Native(b)) if a.ptr_eq(b))
+}
+
+fn identity() -> Value {
+    Value::Closure {
+        code: VecDeque::new(),
+        env: Env::nil(),
+        origin: None,
+    }
+}
+
+// 2引数の関数。本体は2つ目の引数を受け取った後に実行される
+fn binary(body: VecDeque<Instr>, env: Rc<Env>) -> Value {
+    let code = VecDeque::from(vec![Instr::Abs {
+        arity: NonZeroUsize::new(1).unwrap(),
+        body: Rc::new(body),
+        range: None,
+    }]);
+    Value::Closure {
+        code,
+        env,
+        origin: None,
+    }
+}
+
+fn app(func_idx: usize, arg_idx: usize) -> Instr {
+    Instr::App {
+        func_idx: NonZeroUsize::new(func_idx).unwrap(),
+        arg_idx: NonZeroUsize::new(arg_idx).unwrap(),
+        range: None,
+    }
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_prog;
+
+    fn vm() -> VM {
+        VM::resumable(&parse_prog("w").unwrap())
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut vm = vm();
+        assert_eq!(decode_bool(&mut vm, &boolean(true)).unwrap(), Some(true));
+        assert_eq!(decode_bool(&mut vm, &boolean(false)).unwrap(), Some(false));
+        for n in [0, 1, 5] {
+            assert_eq!(decode_numeral(&mut vm, &numeral(n)).unwrap(), Some(n));
+        }
+        assert_eq!(
+            decode_bytes(&mut vm, &bytes(b"grass")).unwrap(),
+            Some(b"grass".to_vec())
+        );
+        assert_eq!(decode_bytes(&mut vm, &bytes(b"")).unwrap(), Some(vec![]));
+
+        let (a, b) = decode_pair(&mut vm, &pair(numeral(2), Value::Char(b'w'.into())))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decode_numeral(&mut vm, &a).unwrap(), Some(2));
+        assert!(matches!(b, Value::Char(c) if c == u32::from(b'w')));
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut vm = vm();
+        assert_eq!(decode_bool(&mut vm, &numeral(2)).unwrap(), None);
+        assert_eq!(
+            decode_bool(&mut vm, &Value::Char(b'w'.into())).unwrap(),
+            None
+        );
+        assert_eq!(decode_numeral(&mut vm, &boolean(true)).unwrap(), None);
+        assert_eq!(decode_numeral(&mut vm, &bytes(b"w")).unwrap(), None);
+        assert_eq!(decode_bytes(&mut vm, &list([numeral(1)])).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_grass_values() {
+        // 2 = λf x. f (f x)、pair = λa b s. s a b
+        let prog = parse_prog("wwWWwWWWwvwwwWwwwWwww").unwrap();
+        let mut vm = VM::resumable(&prog);
+        let defs = vm.definitions().unwrap();
+
+        assert_eq!(decode_numeral(&mut vm, &defs[0]).unwrap(), Some(2));
+
+        let partial = vm.apply(defs[1].clone(), Value::Char(b'a'.into())).unwrap();
+        let p = vm.apply(partial, Value::Char(b'b'.into())).unwrap();
+        let (a, b) = decode_pair(&mut vm, &p).unwrap().unwrap();
+        assert!(matches!(a, Value::Char(c) if c == u32::from(b'a')));
+        assert!(matches!(b, Value::Char(c) if c == u32::from(b'b')));
+    }
+}
@@ -0,0 +1,21 @@
+use crate::parser::Syntax;
+
+// 仕様に挙がっている他の実装が受け付ける文字の違いを再現するための設定。
+// 実行時の動作の違いは仕様に書かれていないので、構文解析だけを切り替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compat {
+    // 仕様通りの動作
+    #[default]
+    Spec,
+    // Standard ML 版。US-ASCII の文字だけを受け付ける
+    Sml,
+}
+
+impl Compat {
+    pub fn syntax(self) -> Syntax {
+        match self {
+            Compat::Spec => Syntax::default(),
+            Compat::Sml => Syntax::ascii(),
+        }
+    }
+}
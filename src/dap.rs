@@ -0,0 +1,736 @@
+use crate::ast::{Prog, SourceRange, Top};
+use crate::debugger::{self, Breakpoint, Condition, Debugger, Mode, Stop};
+use crate::io::SharedBuffer;
+use crate::ir::{Env, Value};
+use crate::parser::parse_prog;
+use crate::pp::{PP, PrettyOptions};
+use crate::stack_trace::StackTrace;
+use crate::vm::{Dialect, InterruptHandle, VM};
+use serde_json::{Value as Json, json};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use thiserror::Error;
+
+// Debug Adapter Protocol でエディタから Debugger を操作する
+pub struct Server<W: Write> {
+    out: W,
+    // 送ったメッセージの通し番号
+    seq: u64,
+    session: Option<Session>,
+    // 応答の後に送るイベント
+    events: Vec<(&'static str, Json)>,
+    // 応答を送った後で、この進め方でプログラムを動かす
+    pending: Option<Mode>,
+    // 実行中に届いた pause などで VM を止める
+    interrupt: InterruptHandle,
+    done: bool,
+}
+
+// launch で起動したプログラム
+struct Session {
+    debugger: Debugger,
+    path: String,
+    prog: Prog,
+    // プログラムの Out を溜めておき、output イベントで送る
+    output: SharedBuffer,
+    stop_on_entry: bool,
+    // setBreakpoints で置いたブレークポイントの番号
+    breakpoints: Vec<usize>,
+    // variablesReference で参照できる環境。番号は添字 + 1 で、止まるたびに捨てる
+    envs: Vec<Rc<Env>>,
+    // variables で値を表示する時の設定
+    pretty: PrettyOptions,
+}
+
+#[derive(Debug, Error)]
+pub enum DapError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("malformed header {0:?}")]
+    Header(String),
+}
+
+// スレッドは一つしか無い
+const THREAD: u64 = 1;
+
+// 実行中のプログラムを止めてから処理するリクエスト
+const INTERRUPTING: [&str; 3] = ["pause", "terminate", "disconnect"];
+
+// r からリクエストを読み、応答とイベントを w に書く。disconnect か入力の終わりで戻る。
+// プログラムを動かしている間もリクエストを受け取れるよう、r は別のスレッドで読む
+pub fn serve<R, W>(mut r: R, w: W) -> Result<(), DapError>
+where
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let mut server = Server::new(w);
+    let interrupt = server.interrupt.clone();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        loop {
+            let message = read_message(&mut r);
+            let interrupting = match &message {
+                Ok(Some(message)) => INTERRUPTING
+                    .iter()
+                    .any(|command| message["command"] == *command),
+                _ => true,
+            };
+            if interrupting {
+                interrupt.cancel();
+            }
+            let end = !matches!(message, Ok(Some(_)));
+            if tx.send(message).is_err() || end {
+                break;
+            }
+        }
+    });
+
+    while !server.done {
+        match rx.recv() {
+            Ok(Ok(Some(message))) => server.dispatch(&message)?,
+            Ok(Ok(None)) | Err(_) => break,
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 0,
+            session: None,
+            events: Vec::new(),
+            pending: None,
+            interrupt: InterruptHandle::default(),
+            done: false,
+        }
+    }
+
+    pub fn dispatch(&mut self, message: &Json) -> Result<(), DapError> {
+        if message["type"] != "request" {
+            return Ok(());
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+        });
+        match self.handle(command, &message["arguments"]) {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response)?;
+        self.send_events()?;
+        if let Some(mode) = self.pending.take() {
+            self.resume(mode);
+            self.send_events()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, command: &str, args: &Json) -> Result<Json, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                if self.session()?.stop_on_entry {
+                    self.events.push(("stopped", stopped("entry")));
+                } else {
+                    self.pending = Some(Mode::Continue);
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "continue" => {
+                self.start(Mode::Continue)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => self.start(Mode::Next).map(|_| json!({})),
+            "stepIn" => self.start(Mode::Step(1)).map(|_| json!({})),
+            "stepOut" => self.start(Mode::Finish).map(|_| json!({})),
+            // 動いていれば読み込み側のスレッドが止めて、stopped イベントを送っている
+            "pause" => {
+                self.session()?;
+                self.interrupt.clear();
+                Ok(json!({}))
+            }
+            "terminate" => {
+                self.interrupt.clear();
+                self.events.push(("terminated", json!({})));
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.done = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("unsupported request {:?}", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("launch needs the path of a program")?;
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path, err))?;
+        let prog =
+            parse_prog(&source).map_err(|err| format!("failed to parse program: {}", err))?;
+        let dialect = match args["dialect"].as_str() {
+            None | Some("standard") => Dialect::Standard,
+            Some("extended") => Dialect::Extended,
+            Some("concurrent") => Dialect::Concurrent,
+            Some("continuation") => Dialect::Continuation,
+            Some(dialect) => return Err(format!("unknown dialect {:?}", dialect)),
+        };
+
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(dialect);
+        vm.set_interrupt_handle(self.interrupt.clone());
+        let output = SharedBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        // 標準入出力はプロトコルに使うので、プログラムの入力は全て引数で受け取る
+        let mut debugger = Debugger::new(vm);
+        debugger.set_input(args["input"].as_str().unwrap_or_default().as_bytes());
+
+        self.session = Some(Session {
+            debugger,
+            path: path.to_string(),
+            prog,
+            output,
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            breakpoints: Vec::new(),
+            envs: Vec::new(),
+            pretty: pretty_options(&args["pretty"]),
+        });
+        // プログラムが決まってからブレークポイントを受け付ける
+        self.events.push(("initialized", json!({})));
+        Ok(json!({}))
+    }
+
+    // 指定された位置の App にブレークポイントを置き直す
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session()?;
+        let requested = args["breakpoints"].as_array().into_iter().flatten();
+        // 起動したプログラム以外のファイルには置けない
+        let path = args["source"]["path"].as_str().unwrap_or_default();
+        if !same_file(path, &session.path) {
+            let breakpoints: Vec<Json> = requested
+                .map(|requested| {
+                    json!({
+                        "verified": false,
+                        "line": requested["line"],
+                        "message": "not the launched program",
+                    })
+                })
+                .collect();
+            return Ok(json!({ "breakpoints": breakpoints }));
+        }
+        for id in session.breakpoints.drain(..) {
+            session.debugger.delete_breakpoint(id);
+        }
+
+        let ranges = app_ranges(&session.prog);
+        let mut breakpoints = Vec::new();
+        for requested in requested {
+            let line = requested["line"].as_i64().unwrap_or_default() as i32;
+            let column = requested["column"].as_i64().map(|column| column as i32);
+            let condition = match requested["condition"].as_str() {
+                Some(condition) => match condition.parse::<Condition>() {
+                    Ok(condition) => Some(condition),
+                    Err(_) => {
+                        breakpoints.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": format!("malformed condition {:?}; expected `depth OP N`", condition),
+                        }));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            if !ranges
+                .iter()
+                .any(|range| debugger::contains(*range, line, column))
+            {
+                breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no application here",
+                }));
+                continue;
+            }
+
+            let id = session.debugger.add_breakpoint(Breakpoint::At {
+                line,
+                column,
+                condition,
+            });
+            session.breakpoints.push(id);
+            breakpoints.push(json!({ "id": id, "verified": true, "line": line }));
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // 応答を送った後で mode の通りに進める
+    fn start(&mut self, mode: Mode) -> Result<(), String> {
+        self.session()?;
+        self.pending = Some(mode);
+        Ok(())
+    }
+
+    fn resume(&mut self, mode: Mode) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let stop = session.debugger.resume(mode);
+        session.envs.clear();
+
+        let output = session.output.take();
+        if !output.is_empty() {
+            self.events.push((
+                "output",
+                json!({ "category": "stdout", "output": String::from_utf8_lossy(&output) }),
+            ));
+        }
+        match stop {
+            Stop::Breakpoint(id) => {
+                let mut body = stopped("breakpoint");
+                body["hitBreakpointIds"] = json!([id]);
+                self.events.push(("stopped", body));
+            }
+            Stop::Step => self.events.push(("stopped", stopped("step"))),
+            Stop::Interrupted => self.events.push(("stopped", stopped("pause"))),
+            // 入力は launch で EOF まで渡しているので、ここには来ない
+            Stop::NeedInput => {
+                let mut body = stopped("pause");
+                body["description"] = json!("waiting for input");
+                self.events.push(("stopped", body));
+            }
+            // 止まった所の状態を見られるよう、終了せずに止める
+            Stop::Error(err) => {
+                let mut message = format!("runtime error: {}\n", err);
+                if let Some(trace) = err.stack_trace() {
+                    message.push_str(&format!("{}\n", trace));
+                }
+                self.events
+                    .push(("output", json!({ "category": "stderr", "output": message })));
+                let mut body = stopped("exception");
+                body["text"] = json!(err.to_string());
+                self.events.push(("stopped", body));
+            }
+            Stop::Halted => self.exit(0),
+            Stop::Exited(code) => self.exit(code),
+            Stop::NotRunning => self.events.push(("terminated", json!({}))),
+        }
+    }
+
+    fn exit(&mut self, code: u32) {
+        self.events.push(("exited", json!({ "exitCode": code })));
+        self.events.push(("terminated", json!({})));
+    }
+
+    // state.dump から作った呼び出しの列。繰り返した呼び出しは一つにまとめる
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let session = self.session()?;
+        let trace = StackTrace::capture(session.debugger.vm().state(), session.debugger.site());
+        let mut frames: Vec<Json> = trace
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let mut name = match frame.function {
+                    Some(range) => format!("fn@{}", range),
+                    None => "<main>".to_string(),
+                };
+                if frame.repeat > 1 {
+                    name.push_str(&format!(" (repeated {} times)", frame.repeat));
+                }
+                let mut json = json!({ "id": id, "name": name, "line": 0, "column": 0 });
+                if let Some(range) = frame.site.or(frame.function) {
+                    json["source"] = json!({ "path": session.path });
+                    json["line"] = json!(range.start.line);
+                    json["column"] = json!(range.start.column);
+                    json["endLine"] = json!(range.end.line);
+                    json["endColumn"] = json!(range.end.column);
+                }
+                json
+            })
+            .collect();
+        if frames.is_empty() {
+            frames.push(json!({ "id": 0, "name": "<main>", "line": 0, "column": 0 }));
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    // 環境を見られるのは実行中の関数だけ
+    fn scopes(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session()?;
+        if args["frameId"].as_u64() != Some(0) {
+            return Ok(json!({ "scopes": [] }));
+        }
+        session.envs.push(session.debugger.vm().state().env.clone());
+        Ok(json!({ "scopes": [{
+            "name": "Environment",
+            "variablesReference": session.envs.len(),
+            "expensive": false,
+        }] }))
+    }
+
+    // 環境の要素を de Bruijn index の順に並べる。クロージャは捕まえた環境を開ける
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session()?;
+        let env = args["variablesReference"]
+            .as_u64()
+            .and_then(|reference| session.envs.get((reference as usize).checked_sub(1)?))
+            .cloned()
+            .ok_or("unknown variables reference")?;
+
+        let mut variables = Vec::new();
+        let mut node = env.as_ref();
+        let mut index = 1;
+        while let Env::Node(value, next) = node {
+            let reference = match value {
+                Value::Closure { env, .. } => {
+                    session.envs.push(env.clone());
+                    session.envs.len()
+                }
+                _ => 0,
+            };
+            variables.push(json!({
+                "name": index.to_string(),
+                "value": format!("{:?}", PP(value, session.pretty)),
+                "variablesReference": reference,
+            }));
+            node = next.as_ref();
+            index += 1;
+        }
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session.as_mut().ok_or_else(not_launched)
+    }
+
+    fn send_events(&mut self) -> io::Result<()> {
+        for (event, body) in std::mem::take(&mut self.events) {
+            self.send(json!({ "type": "event", "event": event, "body": body }))?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Json) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+}
+
+// ========================================================================== //
+
+// Content-Length ヘッダの付いたメッセージを一つ読む。入力が尽きていれば None
+pub fn read_message<R: BufRead>(r: &mut R) -> Result<Option<Json>, DapError> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            let value = value.trim().parse();
+            length = Some(value.map_err(|_| DapError::Header(line.to_string()))?);
+        }
+    }
+    let length = length.ok_or_else(|| DapError::Header(String::new()))?;
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub fn write_message<W: Write>(w: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    w.flush()
+}
+
+fn stopped(reason: &str) -> Json {
+    json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true })
+}
+
+fn not_launched() -> String {
+    "no program has been launched".to_string()
+}
+
+// launch の pretty。省略した項目は既定のまま
+fn pretty_options(args: &Json) -> PrettyOptions {
+    let default = PrettyOptions::default();
+    let size = |key: &str, default: usize| args[key].as_u64().map_or(default, |n| n as usize);
+    PrettyOptions {
+        max_depth: size("maxDepth", default.max_depth),
+        max_len: size("maxLength", default.max_len),
+        show_indices: args["showIndices"]
+            .as_bool()
+            .unwrap_or(default.show_indices),
+        show_origin: args["showOrigin"].as_bool().unwrap_or(default.show_origin),
+    }
+}
+
+// 書き方が違っても同じファイルなら一致とみなす
+fn same_file(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn app_ranges(prog: &Prog) -> Vec<SourceRange> {
+    prog.items
+        .iter()
+        .flat_map(|top| match top {
+            Top::Abs(abs) => abs.body.iter().map(|app| app.range).collect(),
+            Top::App(app) => vec![app.range],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // エディタの代わりに、別のスレッドで動くサーバーとパイプ越しにやり取りする
+    struct Client {
+        path: std::path::PathBuf,
+        input: io::PipeWriter,
+        output: io::BufReader<io::PipeReader>,
+        server: std::thread::JoinHandle<Result<(), DapError>>,
+        seq: u64,
+        messages: Vec<Json>,
+    }
+
+    impl Client {
+        fn new(program: &str) -> Self {
+            // 並行して動くテストが互いのファイルを消さないよう、クライアントごとに名前を変える
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "rusty-grass-dap-{}-{}.grass",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::write(&path, program).unwrap();
+            let (r, input) = io::pipe().unwrap();
+            let (output, w) = io::pipe().unwrap();
+            let server = std::thread::spawn(move || serve(io::BufReader::new(r), w));
+            Self {
+                path,
+                input,
+                output: io::BufReader::new(output),
+                server,
+                seq: 0,
+                messages: Vec::new(),
+            }
+        }
+
+        // 送ったリクエストへの応答が届くまで待つ
+        fn request(&mut self, mut request: Json) {
+            self.seq += 1;
+            request["seq"] = json!(self.seq);
+            request["type"] = json!("request");
+            let path = self.path.to_str().unwrap();
+            if request["command"] == "launch" {
+                request["arguments"]["program"] = json!(path);
+            }
+            if request["command"] == "setBreakpoints" && request["arguments"]["source"].is_null() {
+                request["arguments"]["source"] = json!({ "path": path });
+            }
+            write_message(&mut self.input, &request).unwrap();
+            let seq = self.seq;
+            self.wait(|message| message["request_seq"] == seq);
+        }
+
+        // プログラムが止まるか終わるまで待つ
+        fn wait_stop(&mut self) {
+            self.wait(|message| message["event"] == "stopped" || message["event"] == "terminated");
+        }
+
+        fn wait(&mut self, until: impl Fn(&Json) -> bool) {
+            loop {
+                let message = read_message(&mut self.output).unwrap().unwrap();
+                let done = until(&message);
+                self.messages.push(message);
+                if done {
+                    break;
+                }
+            }
+        }
+
+        // 入力を閉じ、残りのメッセージを全て返す
+        fn finish(mut self) -> Vec<Json> {
+            drop(self.input);
+            while let Some(message) = read_message(&mut self.output).unwrap() {
+                self.messages.push(message);
+            }
+            self.server.join().unwrap().unwrap();
+            std::fs::remove_file(&self.path).ok();
+            self.messages
+        }
+    }
+
+    // 台本通りにリクエストを送り、返ってきたメッセージを全て返す
+    fn session(program: &str, requests: &[Json]) -> Vec<Json> {
+        let mut client = Client::new(program);
+        for request in requests {
+            client.request(request.clone());
+            let command = request["command"].as_str().unwrap();
+            if ["configurationDone", "continue", "next", "stepIn", "stepOut"].contains(&command) {
+                client.wait_stop();
+            }
+        }
+        client.finish()
+    }
+
+    fn events<'a>(messages: &'a [Json], event: &str) -> Vec<&'a Json> {
+        messages
+            .iter()
+            .filter(|message| message["event"] == event)
+            .map(|message| &message["body"])
+            .collect()
+    }
+
+    fn response<'a>(messages: &'a [Json], command: &str) -> &'a Json {
+        messages
+            .iter()
+            .find(|message| message["type"] == "response" && message["command"] == command)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        // λx. Out (In x)。Out は 1:8 の App
+        let messages = session(
+            "wWWWWWwWWWw",
+            &[
+                json!({ "command": "initialize", "arguments": {} }),
+                json!({ "command": "launch", "arguments": { "input": "a" } }),
+                json!({ "command": "setBreakpoints", "arguments": {
+                    "breakpoints": [{ "line": 1, "column": 8 }, { "line": 2 }],
+                } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+                json!({ "command": "scopes", "arguments": { "frameId": 0 } }),
+                json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+                json!({ "command": "next", "arguments": { "threadId": 1 } }),
+                json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped[0]["reason"], "breakpoint");
+        assert_eq!(
+            stopped[0]["hitBreakpointIds"],
+            json!([breakpoints[0]["id"]])
+        );
+        assert_eq!(stopped[1]["reason"], "step");
+
+        let frames = &response(&messages, "stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "<main>");
+        assert_eq!(
+            (&frames[0]["line"], &frames[0]["column"]),
+            (&json!(1), &json!(8))
+        );
+
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(
+            variables[0],
+            json!({ "name": "1", "value": "'a'", "variablesReference": 0 })
+        );
+        assert_ne!(variables[1]["variablesReference"], 0);
+
+        assert_eq!(events(&messages, "output")[0]["output"], "a");
+        assert_eq!(events(&messages, "exited")[0]["exitCode"], 0);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        // λx. x x。自分自身を呼び続ける
+        let messages = session(
+            "wWw",
+            &[
+                json!({ "command": "launch", "arguments": { "pretty": { "showOrigin": false } } }),
+                json!({ "command": "setBreakpoints", "arguments": {
+                    "breakpoints": [{ "line": 1, "condition": "depth > 9" }, { "line": 1, "condition": "deep" }],
+                } }),
+                json!({ "command": "configurationDone" }),
+                json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+                json!({ "command": "scopes", "arguments": { "frameId": 0 } }),
+                json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+                json!({ "command": "disconnect" }),
+            ],
+        );
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(events(&messages, "stopped")[0]["reason"], "breakpoint");
+        let frames = &response(&messages, "stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "fn@1:1 (repeated 10 times)");
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[0]["value"], "<fn arity=1>");
+    }
+
+    #[test]
+    fn test_pause() {
+        // λx. x x。止めない限り終わらない
+        let mut client = Client::new("wWw");
+        client.request(json!({ "command": "initialize", "arguments": {} }));
+        client.request(json!({ "command": "launch", "arguments": {} }));
+        client.request(json!({ "command": "setBreakpoints", "arguments": {
+            "source": { "path": "other.grass" },
+            "breakpoints": [{ "line": 1 }],
+        } }));
+        client.request(json!({ "command": "configurationDone" }));
+        // 動いている VM を止めた stopped イベントが、応答より先に届く
+        client.request(json!({ "command": "pause", "arguments": { "threadId": 1 } }));
+        client.request(json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }));
+        client.request(json!({ "command": "disconnect" }));
+        let messages = client.finish();
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], false);
+        assert_eq!(response(&messages, "pause")["success"], true);
+        assert_eq!(events(&messages, "stopped")[0]["reason"], "pause");
+        assert_eq!(response(&messages, "stackTrace")["success"], true);
+    }
+}
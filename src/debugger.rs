@@ -0,0 +1,709 @@
+use crate::ast::SourceRange;
+use crate::flight_recorder::Rule;
+use crate::io::IoEvent;
+use crate::ir::Env;
+use crate::observer::Observer;
+use crate::pp::{PP, PrettyOptions};
+use crate::stack_trace::StackTrace;
+use crate::vm::{RuntimeError, Status, Suspended, VM};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+use std::str::FromStr;
+use thiserror::Error;
+
+// VM を 1 遷移ずつ進め、ブレークポイントで止める
+pub struct Debugger {
+    vm: VM,
+    // 番号とブレークポイント。番号は 1 から振る
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+    // 直前の遷移で起きた入出力
+    io: Rc<RefCell<IoWatch>>,
+    // 終了した後は restart するまで進められない
+    finished: bool,
+    // restart のたびに EOF と一緒に与え直す入力
+    input: Option<Vec<u8>>,
+    pretty: PrettyOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    // その位置の App を実行する直前。column が無ければ行の中のどの App でも止まる
+    At {
+        line: i32,
+        column: Option<i32>,
+        condition: Option<Condition>,
+    },
+    Out,
+    // EOF を読んだ時も含む
+    In,
+    // dump の深さが条件を満たすようになった時。満たしている間ずっと止まりはしない
+    Depth(Condition),
+}
+
+// dump の深さについての条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub op: Comparison,
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    // 指定した数だけ遷移を進める
+    Step(u64),
+    // 呼び出した関数から戻るまでを 1 歩とみなして進める
+    Next,
+    // 今の関数から戻るまで進める
+    Finish,
+    // None ならブレークポイントの一覧を表示する
+    Break(Option<Breakpoint>),
+    Delete(usize),
+    // None なら環境全体を表示する
+    Print(Option<NonZeroUsize>),
+    Backtrace,
+    Where,
+    // 改行を付けてプログラムの入力にする
+    Input(String),
+    Eof,
+    Restart,
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Error)]
+#[error("{0}; try `help`")]
+pub struct CommandError(String);
+
+#[derive(Default)]
+struct IoWatch {
+    event: Option<IoEvent>,
+}
+
+// resume() の進め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Step(u64),
+    // 呼び出した関数から戻るまでを 1 歩とみなす
+    Next,
+    // 今の関数から戻るまで
+    Finish,
+    Continue,
+}
+
+// resume() が止まった理由
+#[derive(Debug)]
+pub enum Stop {
+    Breakpoint(usize),
+    // Mode で指定しただけ進んだ
+    Step,
+    NeedInput,
+    Interrupted,
+    Halted,
+    Exited(u32),
+    Error(RuntimeError),
+    // 既に終了していて進められない
+    NotRunning,
+}
+
+const HELP: &str = "\
+continue, c          run until a breakpoint is hit or the program ends
+step, s [N]          run N transitions (default 1)
+next, n              run one transition, stepping over function calls
+finish               run until the current function returns
+break, b             list breakpoints
+break L[:C] [if depth OP N]
+                     stop before an application at line L, column C
+break out | in       stop after Out or In is applied
+break depth OP N     stop when the dump depth starts to satisfy OP N (OP is <, <=, ==, >= or >)
+delete, d ID         delete a breakpoint
+print, p [I]         print the environment, or its entry at de Bruijn index I
+backtrace, bt        print the functions being called
+where, w             print the next transition
+input TEXT           feed TEXT and a newline to the program
+eof                  close the program's input
+restart              start the program over
+quit, q              exit the debugger";
+
+impl Debugger {
+    // vm は VM::resumable() などで、ホストから入力を受け取るように作っておく
+    pub fn new(mut vm: VM) -> Self {
+        let io = Rc::new(RefCell::new(IoWatch::default()));
+        vm.add_observer(Box::new(io.clone()));
+        Self {
+            vm,
+            breakpoints: Vec::new(),
+            next_id: 1,
+            io,
+            finished: false,
+            input: None,
+            pretty: PrettyOptions::default(),
+        }
+    }
+
+    // print で値を表示する時の設定
+    pub fn set_pretty_options(&mut self, options: PrettyOptions) {
+        self.pretty = options;
+    }
+
+    // input と EOF をプログラムに与え、restart した後も同じものを与える
+    pub fn set_input(&mut self, input: &[u8]) {
+        self.input = Some(input.to_vec());
+        self.vm.feed(input);
+        self.vm.feed_eof();
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    // 番号を返す
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.breakpoints.push((id, breakpoint));
+        self.next_id += 1;
+        id
+    }
+
+    pub fn delete_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(i, _)| *i != id);
+        self.breakpoints.len() < len
+    }
+
+    pub fn execute(&mut self, command: Command, out: &mut dyn Write) -> io::Result<()> {
+        match command {
+            Command::Continue => self.report(Mode::Continue, out),
+            Command::Step(n) => self.report(Mode::Step(n), out),
+            Command::Next => self.report(Mode::Next, out),
+            Command::Finish => self.report(Mode::Finish, out),
+            Command::Break(Some(breakpoint)) => {
+                let id = self.add_breakpoint(breakpoint);
+                writeln!(out, "breakpoint {}: {}", id, breakpoint)
+            }
+            Command::Break(None) => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "no breakpoints")?;
+                }
+                for (id, breakpoint) in &self.breakpoints {
+                    writeln!(out, "{:>4}: {}", id, breakpoint)?;
+                }
+                Ok(())
+            }
+            Command::Delete(id) => match self.delete_breakpoint(id) {
+                true => Ok(()),
+                false => writeln!(out, "no breakpoint {}", id),
+            },
+            Command::Print(Some(index)) => match self.vm.state().env.get(index) {
+                Some(value) => writeln!(out, "{:?}", PP(&(index.get(), value), self.pretty)),
+                None => writeln!(out, "index {} is out of bounds", index),
+            },
+            Command::Print(None) => {
+                let mut env = self.vm.state().env.as_ref();
+                let mut index = 1;
+                while let Env::Node(value, next) = env {
+                    writeln!(out, "{:?}", PP(&(index, value), self.pretty))?;
+                    env = next.as_ref();
+                    index += 1;
+                }
+                Ok(())
+            }
+            Command::Backtrace => {
+                let trace = StackTrace::capture(self.vm.state(), self.site());
+                if trace.frames.is_empty() {
+                    writeln!(out, "{:>4}: <main>", 0)
+                } else {
+                    writeln!(out, "{}", trace)
+                }
+            }
+            Command::Where => self.where_(out),
+            Command::Input(text) => {
+                self.vm.feed(text.as_bytes());
+                self.vm.feed(b"\n");
+                Ok(())
+            }
+            Command::Eof => {
+                self.vm.feed_eof();
+                Ok(())
+            }
+            Command::Restart => {
+                self.restart();
+                self.where_(out)
+            }
+            Command::Help => writeln!(out, "{}", HELP),
+            Command::Quit => Ok(()),
+        }
+    }
+
+    fn report(&mut self, mode: Mode, out: &mut dyn Write) -> io::Result<()> {
+        // プロンプトで押された Ctrl-C は、これから始める実行を止めるものではない
+        self.vm.interrupt_handle().clear();
+        match self.resume(mode) {
+            Stop::Breakpoint(id) => {
+                writeln!(out, "breakpoint {}: {}", id, self.breakpoint(id))?;
+                self.where_(out)
+            }
+            Stop::Step => self.where_(out),
+            Stop::NeedInput => writeln!(out, "waiting for input; use `input TEXT` or `eof`"),
+            Stop::Interrupted => {
+                writeln!(out, "interrupted")?;
+                self.where_(out)
+            }
+            Stop::Halted => writeln!(out, "program halted after {} steps", self.vm.steps()),
+            Stop::Exited(code) => writeln!(out, "program exited with status {}", code),
+            Stop::Error(err) => {
+                writeln!(out, "runtime error: {}", err)?;
+                match err.stack_trace() {
+                    Some(trace) => writeln!(out, "{}", trace),
+                    None => Ok(()),
+                }
+            }
+            Stop::NotRunning => writeln!(out, "the program is not running; use `restart`"),
+        }
+    }
+
+    pub fn resume(&mut self, mode: Mode) -> Stop {
+        if self.finished {
+            return Stop::NotRunning;
+        }
+        let start = self.depth();
+        let mut remaining = match mode {
+            Mode::Step(n) => n,
+            _ => 0,
+        };
+        loop {
+            let before = self.depth();
+            match self.vm.run_for(1) {
+                Ok(Status::Suspended(Suspended::OutOfSteps | Suspended::Blocked)) => {}
+                Ok(Status::Suspended(Suspended::NeedInput)) => return Stop::NeedInput,
+                Ok(Status::Halted) => {
+                    self.finished = true;
+                    return Stop::Halted;
+                }
+                Ok(Status::Exited(code)) => {
+                    self.finished = true;
+                    return Stop::Exited(code);
+                }
+                // 中断は遷移の前に確かめるので、そのまま続けられる
+                Err(RuntimeError::Interrupted) => return Stop::Interrupted,
+                Err(err) => {
+                    self.finished = true;
+                    return Stop::Error(err);
+                }
+            }
+
+            let depth = self.depth();
+            let event = self.io.borrow_mut().event.take();
+            if let Some(id) = self.hit(event, before, depth) {
+                return Stop::Breakpoint(id);
+            }
+            let done = match mode {
+                Mode::Step(_) => {
+                    remaining = remaining.saturating_sub(1);
+                    remaining == 0
+                }
+                Mode::Next => depth <= start,
+                Mode::Finish => depth < start,
+                Mode::Continue => false,
+            };
+            if done {
+                return Stop::Step;
+            }
+        }
+    }
+
+    pub fn restart(&mut self) {
+        self.vm.reset();
+        if let Some(input) = &self.input {
+            self.vm.feed(input);
+            self.vm.feed_eof();
+        }
+        self.io.borrow_mut().event = None;
+        self.finished = false;
+    }
+
+    // 直前の遷移の後で止まるブレークポイントの番号
+    fn hit(&self, event: Option<IoEvent>, before: usize, depth: usize) -> Option<usize> {
+        let site = self.site();
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint)| match *breakpoint {
+                Breakpoint::At {
+                    line,
+                    column,
+                    condition,
+                } => {
+                    site.is_some_and(|range| contains(range, line, column))
+                        && condition.is_none_or(|condition| condition.holds(depth))
+                }
+                Breakpoint::Out => matches!(event, Some(IoEvent::Out(_))),
+                Breakpoint::In => matches!(event, Some(IoEvent::In(_) | IoEvent::Eof)),
+                Breakpoint::Depth(condition) => condition.holds(depth) && !condition.holds(before),
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn breakpoint(&self, id: usize) -> Breakpoint {
+        self.breakpoints
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, breakpoint)| *breakpoint)
+            .unwrap()
+    }
+
+    fn where_(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "step {}: {} depth={}",
+            self.vm.steps(),
+            Rule::of(self.vm.state()),
+            self.depth()
+        )
+    }
+
+    // 次に実行する App の位置
+    pub fn site(&self) -> Option<SourceRange> {
+        match Rule::of(self.vm.state()) {
+            Rule::App { range, .. } => range,
+            _ => None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.vm.state().dump.len()
+    }
+}
+
+impl Observer for IoWatch {
+    fn on_io(&mut self, event: IoEvent) {
+        self.event = Some(event);
+    }
+}
+
+impl Condition {
+    fn holds(self, depth: usize) -> bool {
+        match self.op {
+            Comparison::Lt => depth < self.depth,
+            Comparison::Le => depth <= self.depth,
+            Comparison::Eq => depth == self.depth,
+            Comparison::Ge => depth >= self.depth,
+            Comparison::Gt => depth > self.depth,
+        }
+    }
+
+    // `depth OP N` を読む
+    fn parse(words: &[&str]) -> Option<Self> {
+        let ["depth", op, depth] = words else {
+            return None;
+        };
+        let op = match *op {
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            "==" => Comparison::Eq,
+            ">=" => Comparison::Ge,
+            ">" => Comparison::Gt,
+            _ => return None,
+        };
+        Some(Self {
+            op,
+            depth: depth.parse().ok()?,
+        })
+    }
+}
+
+impl FromStr for Condition {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        Condition::parse(&words).ok_or_else(|| CommandError(format!("malformed condition {:?}", s)))
+    }
+}
+
+impl FromStr for Breakpoint {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || CommandError(format!("malformed breakpoint {:?}", s));
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["out"] => Ok(Breakpoint::Out),
+            ["in"] => Ok(Breakpoint::In),
+            ["depth", ..] => Condition::parse(&words)
+                .map(Breakpoint::Depth)
+                .ok_or_else(malformed),
+            [position, rest @ ..] => {
+                let (line, column) = match position.split_once(':') {
+                    Some((line, column)) => (line, Some(column)),
+                    None => (*position, None),
+                };
+                let line = line.parse().map_err(|_| malformed())?;
+                let column = match column {
+                    Some(column) => Some(column.parse().map_err(|_| malformed())?),
+                    None => None,
+                };
+                let condition = match rest {
+                    [] => None,
+                    ["if", condition @ ..] => {
+                        Some(Condition::parse(condition).ok_or_else(malformed)?)
+                    }
+                    _ => return Err(malformed()),
+                };
+                Ok(Breakpoint::At {
+                    line,
+                    column,
+                    condition,
+                })
+            }
+            [] => Err(malformed()),
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let malformed = || CommandError(format!("malformed arguments to {}", name));
+        let rest = rest.trim();
+        let command = match name {
+            "continue" | "c" => Command::Continue,
+            "step" | "s" if rest.is_empty() => Command::Step(1),
+            "step" | "s" => Command::Step(rest.parse().map_err(|_| malformed())?),
+            "next" | "n" => Command::Next,
+            "finish" => Command::Finish,
+            "break" | "b" if rest.is_empty() => Command::Break(None),
+            "break" | "b" => Command::Break(Some(rest.parse()?)),
+            "delete" | "d" => Command::Delete(rest.parse().map_err(|_| malformed())?),
+            "print" | "p" if rest.is_empty() => Command::Print(None),
+            "print" | "p" => Command::Print(Some(rest.parse().map_err(|_| malformed())?)),
+            "backtrace" | "bt" => Command::Backtrace,
+            "where" | "w" => Command::Where,
+            // 前後の空白も入力の一部として残す
+            "input" => Command::Input(s.split_once(' ').map_or("", |(_, text)| text).to_string()),
+            "eof" => Command::Eof,
+            "restart" => Command::Restart,
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(CommandError(format!("unknown command {:?}", name))),
+        };
+        match command {
+            Command::Step(0) => Err(malformed()),
+            Command::Continue
+            | Command::Next
+            | Command::Finish
+            | Command::Backtrace
+            | Command::Where
+            | Command::Eof
+            | Command::Restart
+            | Command::Help
+            | Command::Quit
+                if !rest.is_empty() =>
+            {
+                Err(malformed())
+            }
+            command => Ok(command),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breakpoint::At {
+                line,
+                column,
+                condition,
+            } => {
+                write!(f, "at {}", line)?;
+                if let Some(column) = column {
+                    write!(f, ":{}", column)?;
+                }
+                match condition {
+                    Some(condition) => write!(f, " if {}", condition),
+                    None => Ok(()),
+                }
+            }
+            Breakpoint::Out => write!(f, "out"),
+            Breakpoint::In => write!(f, "in"),
+            Breakpoint::Depth(condition) => write!(f, "{}", condition),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Eq => "==",
+            Comparison::Ge => ">=",
+            Comparison::Gt => ">",
+        };
+        write!(f, "depth {} {}", op, self.depth)
+    }
+}
+
+// ========================================================================== //
+
+// range が line 行 column 列を含むかどうか。column が無ければ行だけで比べる
+pub(crate) fn contains(range: SourceRange, line: i32, column: Option<i32>) -> bool {
+    match column {
+        Some(column) => {
+            let position = (line, column);
+            (range.start.line, range.start.column) <= position
+                && position < (range.end.line, range.end.column)
+        }
+        None => range.start.line <= line && line <= range.end.line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_prog;
+
+    fn debugger(src: &str) -> Debugger {
+        let mut vm = VM::resumable(&parse_prog(src).unwrap());
+        vm.set_output(Box::new(io::sink()));
+        Debugger::new(vm)
+    }
+
+    fn run(debugger: &mut Debugger, command: &str) -> String {
+        let mut out = Vec::new();
+        debugger
+            .execute(command.parse().unwrap(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("s 3".parse::<Command>().unwrap(), Command::Step(3));
+        assert_eq!(
+            "b 1:4 if depth > 2".parse::<Command>().unwrap(),
+            Command::Break(Some(Breakpoint::At {
+                line: 1,
+                column: Some(4),
+                condition: Some(Condition {
+                    op: Comparison::Gt,
+                    depth: 2,
+                }),
+            }))
+        );
+        assert_eq!(
+            "input  a b".parse::<Command>().unwrap(),
+            Command::Input(" a b".to_string())
+        );
+        for s in ["s 0", "b depth 3", "b 1:x", "b 1 if", "next 1", "jump"] {
+            assert!(s.parse::<Command>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_break_on_io() {
+        // λx. Out (In x)
+        let mut debugger = debugger("wWWWWWwWWWw");
+        run(&mut debugger, "break in");
+        run(&mut debugger, "break out");
+        let out = run(&mut debugger, "continue");
+        assert!(out.starts_with("waiting for input"), "{}", out);
+
+        run(&mut debugger, "input a");
+        let out = run(&mut debugger, "c");
+        assert!(out.starts_with("breakpoint 1: in\n"), "{}", out);
+        assert_eq!(run(&mut debugger, "p 1"), "1: 'a'\n");
+        let out = run(&mut debugger, "c");
+        assert!(out.starts_with("breakpoint 2: out\n"), "{}", out);
+        assert_eq!(run(&mut debugger, "c"), "program halted after 5 steps\n");
+        assert!(run(&mut debugger, "c").contains("restart"));
+    }
+
+    #[test]
+    fn test_restart_with_input() {
+        // λx. Out (In x)
+        let mut debugger = debugger("wWWWWWwWWWw");
+        debugger.set_input(b"a");
+        assert_eq!(run(&mut debugger, "c"), "program halted after 5 steps\n");
+        run(&mut debugger, "restart");
+        assert_eq!(run(&mut debugger, "c"), "program halted after 5 steps\n");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // λx. x x。自分自身を呼び続ける
+        let mut debugger = debugger("wWw");
+        let handle = debugger.vm().interrupt_handle();
+        run(&mut debugger, "b depth >= 3");
+
+        // 止まっている間の中断要求は次の実行に持ち越さない
+        handle.cancel();
+        let out = run(&mut debugger, "c");
+        assert!(out.starts_with("breakpoint 1: depth >= 3\n"), "{}", out);
+
+        // 実行中の中断要求で止まり、続きから再開できる
+        run(&mut debugger, "d 1");
+        handle.cancel();
+        assert!(matches!(debugger.resume(Mode::Continue), Stop::Interrupted));
+        assert_eq!(debugger.depth(), 3);
+    }
+
+    #[test]
+    fn test_pretty_options() {
+        // λx. x x
+        let mut debugger = debugger("wWw");
+        run(&mut debugger, "s");
+        assert_eq!(run(&mut debugger, "p 1"), "1: <fn@1:1 arity=1>\n");
+        debugger.set_pretty_options(PrettyOptions {
+            show_indices: false,
+            show_origin: false,
+            ..PrettyOptions::default()
+        });
+        assert_eq!(run(&mut debugger, "p 1"), "<fn arity=1>\n");
+    }
+
+    #[test]
+    fn test_depth() {
+        // λx. x x。自分自身を呼び続けて dump が伸びていく
+        let mut debugger = debugger("wWw");
+        run(&mut debugger, "break depth >= 3");
+        let out = run(&mut debugger, "c");
+        assert!(out.ends_with("depth=3\n"), "{}", out);
+        assert_eq!(
+            run(&mut debugger, "bt"),
+            "   0: fn@1:1 at 1:2 (repeated 3 times)\n   1: <main> at 1:2\n"
+        );
+
+        // next は呼び出した関数が戻るのを待つので、条件を満たし始めた所で止まる
+        run(&mut debugger, "d 1");
+        run(&mut debugger, "b depth > 5");
+        let out = run(&mut debugger, "next");
+        assert!(out.starts_with("breakpoint 2: depth > 5\n"), "{}", out);
+
+        // 条件付きの位置のブレークポイント
+        run(&mut debugger, "restart");
+        run(&mut debugger, "d 2");
+        run(&mut debugger, "b 1:2 if depth == 4");
+        let out = run(&mut debugger, "c");
+        assert!(out.ends_with("App(1, 1) at 1:2 depth=4\n"), "{}", out);
+    }
+}
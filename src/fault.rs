@@ -0,0 +1,127 @@
+use crate::io::Fetch;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+// In と Out に注入する故障の組。回数は 1 から数える
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    faults: Vec<Fault>,
+    // 終わった In と Out の回数
+    ins: u64,
+    outs: u64,
+}
+
+// `eof@N`, `byte@N=B`, `out-error@N` と書く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    // N 回目以降の In は入力を読まずに EOF を返す
+    Eof(u64),
+    // N 回目の In は入力を読まずに byte を返す
+    Byte(u64, u8),
+    // N 回目の Out は書き出さずに I/O エラーになる
+    OutError(u64),
+}
+
+#[derive(Debug, Error)]
+#[error("malformed fault {0:?}: expected eof@N, byte@N=B or out-error@N")]
+pub struct FaultParseError(String);
+
+impl FaultPlan {
+    pub fn new(faults: impl IntoIterator<Item = Fault>) -> Self {
+        Self {
+            faults: faults.into_iter().collect(),
+            ins: 0,
+            outs: 0,
+        }
+    }
+
+    // 次の In で入力の代わりに返すもの
+    pub(crate) fn input(&self) -> Option<Fetch> {
+        let n = self.ins + 1;
+        if self
+            .faults
+            .iter()
+            .any(|f| matches!(f, Fault::Eof(at) if *at <= n))
+        {
+            return Some(Fetch::Eof);
+        }
+        self.faults.iter().find_map(|f| match f {
+            Fault::Byte(at, byte) if *at == n => Some(Fetch::Char((*byte).into())),
+            _ => None,
+        })
+    }
+
+    // 次の Out を失敗させるかどうか
+    pub(crate) fn output_fails(&self) -> bool {
+        let n = self.outs + 1;
+        self.faults.contains(&Fault::OutError(n))
+    }
+
+    pub(crate) fn count_in(&mut self) {
+        self.ins += 1;
+    }
+
+    pub(crate) fn count_out(&mut self) -> u64 {
+        self.outs += 1;
+        self.outs
+    }
+
+    // 回数を数え直す
+    pub(crate) fn rewind(&mut self) {
+        self.ins = 0;
+        self.outs = 0;
+    }
+}
+
+impl FromStr for Fault {
+    type Err = FaultParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || FaultParseError(s.to_string());
+        let (kind, rest) = s.split_once('@').ok_or_else(malformed)?;
+        let fault = match kind {
+            "eof" => Fault::Eof(rest.parse().map_err(|_| malformed())?),
+            "byte" => {
+                let (n, byte) = rest.split_once('=').ok_or_else(malformed)?;
+                Fault::Byte(
+                    n.parse().map_err(|_| malformed())?,
+                    byte.parse().map_err(|_| malformed())?,
+                )
+            }
+            "out-error" => Fault::OutError(rest.parse().map_err(|_| malformed())?),
+            _ => return Err(malformed()),
+        };
+        match fault {
+            Fault::Eof(0) | Fault::Byte(0, _) | Fault::OutError(0) => Err(malformed()),
+            fault => Ok(fault),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Eof(n) => write!(f, "eof@{}", n),
+            Fault::Byte(n, byte) => write!(f, "byte@{}={}", n, byte),
+            Fault::OutError(n) => write!(f, "out-error@{}", n),
+        }
+    }
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for s in ["eof@3", "byte@2=97", "out-error@1"] {
+            assert_eq!(s.parse::<Fault>().unwrap().to_string(), s);
+        }
+        for s in ["eof", "eof@0", "byte@2", "byte@2=256", "out@1"] {
+            assert!(s.parse::<Fault>().is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use crate::ast::SourceRange;
+use crate::io::IoEvent;
+use crate::ir::{Env, Instr, State};
+use std::collections::VecDeque;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+// 直近の遷移を一定数だけ覚えておくリングバッファ
+#[derive(Debug, Clone)]
+pub struct FlightRecorder {
+    capacity: usize,
+    transitions: VecDeque<Transition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub step: u64,
+    pub rule: Rule,
+    // 深さは書き出す時まで数えない
+    env: Rc<Env>,
+    pub dump_depth: usize,
+    pub io: Option<IoEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    App {
+        func_idx: NonZeroUsize,
+        arg_idx: NonZeroUsize,
+        range: Option<SourceRange>,
+    },
+    Abs {
+        arity: NonZeroUsize,
+        range: Option<SourceRange>,
+    },
+    // 関数本体を実行し終えて呼び出し元に戻る
+    Return,
+    // dump が空になり、結果のクロージャを自分自身に適用する
+    SelfApply,
+}
+
+impl FlightRecorder {
+    pub const DEFAULT_CAPACITY: usize = 64;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            transitions: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn transitions(&self) -> impl Iterator<Item = &Transition> {
+        self.transitions.iter()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.transitions.clear();
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub(crate) fn push(&mut self, transition: Transition) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.transitions.len() == self.capacity {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+    }
+}
+
+impl Default for FlightRecorder {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl Rule {
+    // state から次に行う遷移
+    pub fn of(state: &State) -> Self {
+        match state.code.front() {
+            Some(Instr::App {
+                func_idx,
+                arg_idx,
+                range,
+            }) => Rule::App {
+                func_idx: *func_idx,
+                arg_idx: *arg_idx,
+                range: *range,
+            },
+            Some(Instr::Abs { arity, range, .. }) => Rule::Abs {
+                arity: *arity,
+                range: *range,
+            },
+            None if state.dump.is_empty() => Rule::SelfApply,
+            None => Rule::Return,
+        }
+    }
+}
+
+impl Transition {
+    // 遷移を実行する直前の状態を控える
+    pub(crate) fn begin(state: &State, step: u64) -> Self {
+        Transition {
+            step,
+            rule: Rule::of(state),
+            env: state.env.clone(),
+            dump_depth: state.dump.len(),
+            io: None,
+        }
+    }
+
+    pub fn env_depth(&self) -> usize {
+        let mut depth = 0;
+        let mut env = self.env.as_ref();
+        while let Env::Node(_, next) = env {
+            depth += 1;
+            env = next.as_ref();
+        }
+        depth
+    }
+}
+
+impl fmt::Display for FlightRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "flight recorder: last {} transitions",
+            self.transitions.len()
+        )?;
+        for transition in &self.transitions {
+            write!(f, "\n{}", transition)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10}: {} env={} dump={}",
+            self.step,
+            self.rule,
+            self.env_depth(),
+            self.dump_depth
+        )?;
+        match self.io {
+            Some(IoEvent::In(c)) => write!(f, " in {:?}", to_char(c)),
+            Some(IoEvent::Eof) => write!(f, " in EOF"),
+            Some(IoEvent::Out(c)) => write!(f, " out {:?}", to_char(c)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn to_char(c: u32) -> char {
+    char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let range = match self {
+            Rule::App {
+                func_idx,
+                arg_idx,
+                range,
+            } => {
+                write!(f, "App({}, {})", func_idx, arg_idx)?;
+                range
+            }
+            Rule::Abs { arity, range } => {
+                write!(f, "Abs({})", arity)?;
+                range
+            }
+            Rule::Return => return write!(f, "Return"),
+            Rule::SelfApply => return write!(f, "SelfApply"),
+        };
+        match range {
+            Some(range) => write!(f, " at {}", range),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+use thiserror::Error;
+use tracing::debug;
+
+pub(crate) struct Input {
+    source: Source,
+    pending: VecDeque<u8>,
+    eof: bool,
+    recorder: Option<Box<dyn Write>>,
+}
+
+pub(crate) enum Source {
+    // 標準入力からブロッキングで読む
+    Stdin,
+    // ホストが feed したバイト列だけを読む
+    Host,
+    // 記録済みのセッションを再生する
+    Replay(Replay),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fetch {
+    // バイトモードでは 1 バイト、Unicode モードでは UTF-8 で復号した符号位置
+    Char(u32),
+    Eof,
+    // ホストからの入力待ち
+    Pending,
+    // 再生するセッションが尽きた
+    Exhausted,
+    // 記録と異なるステップで EOF を読もうとした
+    Diverged { expected: u64 },
+}
+
+// In と Out で起きた入出力
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoEvent {
+    In(u32),
+    Eof,
+    Out(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Byte(u8),
+    // EOF が返ったステップ番号
+    Eof(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("failed to access session log: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed session log at line {0}")]
+    Malformed(usize),
+}
+
+// In が返した値の記録。`byte <n>` と `eof <step>` を1行ずつ並べたテキスト
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    events: Vec<Event>,
+    // 次に再生する位置
+    next: usize,
+}
+
+// 書き込まれたバイト列を溜めておく Write。複製は同じバッファを共有するので、
+// VM::set_output() に渡した後も手元の複製から読める
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+enum Raw {
+    Byte(u8),
+    Other(Fetch),
+}
+
+impl Input {
+    pub(crate) fn new(source: Source) -> Self {
+        Self {
+            source,
+            pending: VecDeque::new(),
+            eof: false,
+            recorder: None,
+        }
+    }
+
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+    }
+
+    pub(crate) fn feed_eof(&mut self) {
+        self.eof = true;
+    }
+
+    // バッファ済みの入力と EOF を捨て、再生中のセッションは先頭に戻す
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.eof = false;
+        if let Source::Replay(replay) = &mut self.source {
+            replay.next = 0;
+        }
+    }
+
+    pub(crate) fn replay(&mut self, replay: Replay) {
+        self.source = Source::Replay(replay);
+    }
+
+    pub(crate) fn record(&mut self, w: Box<dyn Write>) {
+        self.recorder = Some(w);
+    }
+
+    pub(crate) fn fetch(&mut self, step: u64) -> io::Result<Fetch> {
+        match self.read(step) {
+            Raw::Byte(byte) => {
+                self.consume(&[byte], false, step)?;
+                Ok(Fetch::Char(byte.into()))
+            }
+            Raw::Other(fetch) => {
+                self.consume(&[], fetch == Fetch::Eof, step)?;
+                Ok(fetch)
+            }
+        }
+    }
+
+    // 入力を消費せずに、次に読めるものを調べる。EOF は In と同じく記録する
+    pub(crate) fn peek(&mut self, step: u64) -> io::Result<Fetch> {
+        match self.read(step) {
+            Raw::Byte(byte) => {
+                self.pending.push_front(byte);
+                Ok(Fetch::Char(byte.into()))
+            }
+            Raw::Other(fetch) => {
+                self.consume(&[], fetch == Fetch::Eof, step)?;
+                Ok(fetch)
+            }
+        }
+    }
+
+    // UTF-8 を 1 文字分読む。不正な並びは U+FFFD として読み飛ばす
+    pub(crate) fn fetch_utf8(&mut self, step: u64) -> io::Result<Fetch> {
+        let lead = match self.read(step) {
+            Raw::Byte(byte) => byte,
+            Raw::Other(fetch) => {
+                self.consume(&[], fetch == Fetch::Eof, step)?;
+                return Ok(fetch);
+            }
+        };
+        let len = match lead {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        let mut buf = vec![lead];
+        let mut eof = false;
+        while buf.len() < len {
+            match self.read(step) {
+                Raw::Byte(byte) if byte & 0xc0 == 0x80 => buf.push(byte),
+                // 次の文字の先頭なので読み戻す
+                Raw::Byte(byte) => {
+                    self.pending.push_front(byte);
+                    break;
+                }
+                // 続きが届くまで、読んだ分を戻して待つ
+                Raw::Other(Fetch::Pending) => {
+                    for &byte in buf.iter().rev() {
+                        self.pending.push_front(byte);
+                    }
+                    return Ok(Fetch::Pending);
+                }
+                Raw::Other(Fetch::Eof) => {
+                    eof = true;
+                    break;
+                }
+                Raw::Other(fetch) => return Ok(fetch),
+            }
+        }
+        self.consume(&buf, eof, step)?;
+        let c = std::str::from_utf8(&buf)
+            .ok()
+            .and_then(|s| s.chars().next())
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        Ok(Fetch::Char(c.into()))
+    }
+
+    // 実際に消費した入力だけを記録する
+    fn consume(&mut self, bytes: &[u8], eof: bool, step: u64) -> io::Result<()> {
+        let Some(recorder) = self.recorder.as_mut() else {
+            return Ok(());
+        };
+        for &byte in bytes {
+            write_event(recorder, Event::Byte(byte))?;
+        }
+        if eof {
+            write_event(recorder, Event::Eof(step))?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, step: u64) -> Raw {
+        // 読み戻したバイトはどの入力元よりも先に返す
+        if let Some(byte) = self.pending.pop_front() {
+            return Raw::Byte(byte);
+        }
+        if let Source::Replay(replay) = &mut self.source {
+            let event = replay.events.get(replay.next).copied();
+            if event.is_some() {
+                replay.next += 1;
+            }
+            return match event {
+                Some(Event::Byte(byte)) => Raw::Byte(byte),
+                Some(Event::Eof(expected)) if expected == step => Raw::Other(Fetch::Eof),
+                Some(Event::Eof(expected)) => Raw::Other(Fetch::Diverged { expected }),
+                None => Raw::Other(Fetch::Exhausted),
+            };
+        }
+        if self.eof {
+            return Raw::Other(Fetch::Eof);
+        }
+        match self.source {
+            Source::Stdin => {
+                // 入力を待つ前に、プロンプトなど改行で終わらない出力も表示しておく
+                std::io::stdout().flush().ok();
+                let mut buf = [0u8; 1];
+                match std::io::stdin().read(&mut buf) {
+                    Ok(1) => {
+                        debug!("io: stdin: byte={} {:?}", buf[0], buf[0] as char);
+                        Raw::Byte(buf[0])
+                    }
+                    _ => Raw::Other(Fetch::Eof),
+                }
+            }
+            Source::Host => Raw::Other(Fetch::Pending),
+            Source::Replay(_) => unreachable!(),
+        }
+    }
+}
+
+impl Replay {
+    pub fn new(events: impl IntoIterator<Item = Event>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+            next: 0,
+        }
+    }
+
+    pub fn read<R: BufRead>(r: R) -> Result<Self, SessionError> {
+        let mut events = Vec::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let event = match line.split_once(' ') {
+                Some(("byte", n)) => n.parse().map(Event::Byte).ok(),
+                Some(("eof", step)) => step.parse().map(Event::Eof).ok(),
+                _ => None,
+            };
+            events.push(event.ok_or(SessionError::Malformed(i + 1))?);
+        }
+        Ok(Self::new(events))
+    }
+}
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    // 溜まったバイト列を取り出し、バッファを空にする
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_event(w: &mut Box<dyn Write>, event: Event) -> io::Result<()> {
+    match event {
+        Event::Byte(byte) => writeln!(w, "byte {}", byte),
+        Event::Eof(step) => writeln!(w, "eof {}", step),
+    }
+}
@@ -1,5 +1,8 @@
-use crate::ast;
+use crate::ast::{self, SourceRange};
+use crate::vm::{RuntimeError, VM};
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::fmt;
 use std::num::NonZeroUsize;
 use std::rc::Rc;
 
@@ -8,10 +11,13 @@ pub enum Instr {
     App {
         func_idx: NonZeroUsize,
         arg_idx: NonZeroUsize,
+        range: Option<SourceRange>,
     },
     Abs {
         arity: NonZeroUsize,
-        body: Code,
+        // 同じ Abs から作った VM やクロージャの間で共有する
+        body: Rc<Code>,
+        range: Option<SourceRange>,
     },
 }
 
@@ -19,20 +25,73 @@ pub type Code = VecDeque<Instr>;
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Char(u8),
-    Closure { code: Code, env: Rc<Env> },
+    Char(u32),
+    Closure {
+        code: Code,
+        env: Rc<Env>,
+        // クロージャを作った Abs の位置
+        origin: Option<SourceRange>,
+    },
     Prim(Prim),
+    Native(Native),
+    // call-by-need で適用を遅延したもの
+    Thunk(Thunk),
+    // CallCC が捕まえた継続。適用すると捕まえた時の状態に戻り、引数を CallCC の結果にする
+    Continuation(Rc<State>),
 }
 
+// Grass から呼び出せる Rust の関数
+#[derive(Clone)]
+pub struct Native(Rc<NativeFn>);
+
+type NativeFn = dyn Fn(&mut VM, Value) -> Result<Value, RuntimeError>;
+
+// 評価結果を共有するため、最初に評価した時に結果で置き換える
+#[derive(Clone)]
+pub struct Thunk(Rc<RefCell<ThunkState>>);
+
 #[derive(Debug, Clone)]
+pub enum ThunkState {
+    Delayed {
+        func: Value,
+        arg: Value,
+        site: Option<SourceRange>,
+    },
+    // 評価中。ここで再び評価しようとした場合は自分自身に依存している。
+    // 評価を途中で捨てた時に戻せるよう、関数と引数は残しておく
+    Forcing {
+        func: Value,
+        arg: Value,
+        site: Option<SourceRange>,
+    },
+    Done(Value),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Prim {
     In,
     Succ,
     Out,
+    // 以下は拡張方言でのみ初期環境に置かれる
+    Exit,
+    Pred,
+    Fail,
+    Eof,
+    // 以下は並行方言でのみ初期環境に置かれる
+    Spawn,
+    Send,
+    Recv,
+    // Spawn が作った双方向のチャネルの片側。n と n ^ 1 が対になる
+    Channel(u32),
+    // チャネルを与えた Send。値を与えるとチャネルに送る
+    Sender(u32),
+    // 以下は継続の方言でのみ初期環境に置かれる
+    CallCC,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Env {
+    #[default]
     Empty,
     Node(Value, Rc<Env>),
 }
@@ -41,6 +100,27 @@ pub enum Env {
 pub struct Frame {
     pub code: Code,
     pub env: Rc<Env>,
+    // この Frame から関数を呼び出した App の位置と、呼ばれた関数の定義位置
+    pub call_site: Option<SourceRange>,
+    pub callee: Option<SourceRange>,
+    // call-by-need でサンクを評価している間、返ってきた値の使い道
+    pub demand: Option<Demand>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Demand {
+    // 値でサンクを置き換える
+    Update(Thunk),
+    // 値を関数として arg に適用する
+    ApplyTo {
+        arg: Value,
+        site: Option<SourceRange>,
+    },
+    // 値を引数として func に渡す
+    ArgOf {
+        func: Value,
+        site: Option<SourceRange>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -71,7 +151,8 @@ impl From<&ast::Abs> for Instr {
     fn from(abs: &ast::Abs) -> Self {
         Instr::Abs {
             arity: NonZeroUsize::new(abs.arity).unwrap(),
-            body: abs.body.iter().map(Instr::from).collect(),
+            body: Rc::new(abs.body.iter().map(Instr::from).collect()),
+            range: Some(abs.range),
         }
     }
 }
@@ -81,10 +162,83 @@ impl From<&ast::App> for Instr {
         Instr::App {
             func_idx: NonZeroUsize::new(app.func_idx).unwrap(),
             arg_idx: NonZeroUsize::new(app.arg_idx).unwrap(),
+            range: Some(app.range),
         }
     }
 }
 
+impl Native {
+    pub fn new(f: impl Fn(&mut VM, Value) -> Result<Value, RuntimeError> + 'static) -> Self {
+        Native(Rc::new(f))
+    }
+
+    pub fn call(&self, vm: &mut VM, arg: Value) -> Result<Value, RuntimeError> {
+        (self.0)(vm, arg)
+    }
+
+    pub fn ptr_eq(&self, other: &Native) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn addr(&self) -> usize {
+        Rc::as_ptr(&self.0) as *const () as usize
+    }
+}
+
+impl Thunk {
+    pub fn new(func: Value, arg: Value, site: Option<SourceRange>) -> Self {
+        Thunk(Rc::new(RefCell::new(ThunkState::Delayed {
+            func,
+            arg,
+            site,
+        })))
+    }
+
+    // 評価済みなら値を返す
+    pub fn value(&self) -> Option<Value> {
+        match &*self.0.borrow() {
+            ThunkState::Done(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn state(&self) -> ThunkState {
+        self.0.borrow().clone()
+    }
+
+    pub(crate) fn replace(&self, state: ThunkState) -> ThunkState {
+        self.0.replace(state)
+    }
+
+    // 評価中のまま捨てられたサンクを評価前に戻す
+    pub(crate) fn abandon(&self) {
+        let mut state = self.0.borrow_mut();
+        if let ThunkState::Forcing { func, arg, site } = &*state {
+            *state = ThunkState::Delayed {
+                func: func.clone(),
+                arg: arg.clone(),
+                site: *site,
+            };
+        }
+    }
+
+    pub(crate) fn addr(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+}
+
+impl fmt::Debug for Thunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thunk({:p})", Rc::as_ptr(&self.0))
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({:p})", Rc::as_ptr(&self.0))
+    }
+}
+
 impl Env {
     pub fn nil() -> Rc<Self> {
         Rc::new(Env::Empty)
@@ -108,9 +262,3 @@ impl Env {
         }
     }
 }
-
-impl Default for Env {
-    fn default() -> Self {
-        Env::Empty
-    }
-}
@@ -1,5 +1,21 @@
-mod ast;
-mod ir;
+pub mod ast;
+pub mod church;
+pub mod compat;
+pub mod dap;
+pub mod debugger;
+pub mod fault;
+pub mod flight_recorder;
+pub mod io;
+pub mod ir;
+mod loop_detector;
+pub mod observer;
 pub mod parser;
-mod pp;
+pub mod pipeline;
+pub mod pp;
+mod scheduler;
+pub mod snapshot;
+pub mod stack_trace;
+#[cfg(unix)]
+pub mod terminal;
+pub mod trace;
 pub mod vm;
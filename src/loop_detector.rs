@@ -0,0 +1,189 @@
+use crate::ast::SourceRange;
+use crate::ir::{Code, Env, Instr, Prim, State, Value};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// 入出力を挟まずに同じ構成へ戻ってきたことを検出する
+pub(crate) struct LoopDetector {
+    capacity: usize,
+    // 構成ごとに最初に訪れたステップ。
+    // 指紋に使った Env を保持して、解放されたアドレスが再利用されないようにする
+    visited: HashMap<Fingerprint, (u64, [Rc<Env>; 2])>,
+    // 呼び出された関数の定義位置と、そのステップ
+    entered: Vec<(u64, SourceRange)>,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct Fingerprint {
+    position: Position,
+    // 環境の先頭の値と、それより奥の Env の同一性
+    top: Option<ValueKey>,
+    rest: usize,
+    depth: usize,
+    // 呼び出し元の Frame。その Env は呼び出し元の実行ごとに作られるので、呼び出し元を特定できる
+    caller: Option<(Position, usize)>,
+}
+
+// 次に実行する命令の位置と、残りの命令数。
+// church や apply が組み立てた位置の無いコードは区別できないので、指紋を作らない
+#[derive(PartialEq, Eq, Hash)]
+struct Position {
+    range: Option<SourceRange>,
+    arity: Option<usize>,
+    len: usize,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum ValueKey {
+    Char(u32),
+    Closure { position: Position, env: usize },
+    Prim(Prim),
+    Ptr(usize),
+}
+
+impl LoopDetector {
+    pub(crate) const DEFAULT_CAPACITY: usize = 1 << 16;
+
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            visited: HashMap::new(),
+            entered: Vec::new(),
+        }
+    }
+
+    // 訪れたことのある構成なら、最初に訪れたステップと、それ以降に呼ばれた関数を返す
+    pub(crate) fn visit(&mut self, state: &State, step: u64) -> Option<(u64, Vec<SourceRange>)> {
+        let fingerprint = Fingerprint::of(state)?;
+        if let Some((since, _)) = self.visited.get(&fingerprint) {
+            let since = *since;
+            let mut functions = Vec::new();
+            for (_, origin) in self.entered.iter().filter(|(step, _)| *step >= since) {
+                if !functions.contains(origin) {
+                    functions.push(*origin);
+                }
+            }
+            return Some((since, functions));
+        }
+
+        // 覚えきれなくなったら一から数え直す
+        if self.visited.len() >= self.capacity {
+            self.reset();
+        }
+        let caller_env = match state.dump.last() {
+            Some(frame) => frame.env.clone(),
+            None => Env::nil(),
+        };
+        self.visited
+            .insert(fingerprint, (step, [state.env.clone(), caller_env]));
+        None
+    }
+
+    pub(crate) fn enter(&mut self, step: u64, origin: Option<SourceRange>) {
+        if let Some(origin) = origin {
+            self.entered.push((step, origin));
+        }
+    }
+
+    // 入出力があった時は、それまでの構成には戻れないものとして忘れる
+    pub(crate) fn reset(&mut self) {
+        self.visited.clear();
+        self.entered.clear();
+    }
+}
+
+impl Fingerprint {
+    fn of(state: &State) -> Option<Self> {
+        let (top, rest) = match state.env.as_ref() {
+            Env::Node(value, next) => (Some(ValueKey::of(value)?), Rc::as_ptr(next) as usize),
+            Env::Empty => (None, Rc::as_ptr(&state.env) as usize),
+        };
+        let caller = match state.dump.last() {
+            Some(frame) => Some((Position::of(&frame.code)?, Rc::as_ptr(&frame.env) as usize)),
+            None => None,
+        };
+        Some(Fingerprint {
+            position: Position::of(&state.code)?,
+            top,
+            rest,
+            depth: state.dump.len(),
+            caller,
+        })
+    }
+}
+
+impl Position {
+    fn of(code: &Code) -> Option<Self> {
+        let (range, arity) = match code.front() {
+            Some(Instr::App { range, .. }) => (Some((*range)?), None),
+            Some(Instr::Abs { arity, range, .. }) => (Some((*range)?), Some(arity.get())),
+            None => (None, None),
+        };
+        Some(Position {
+            range,
+            arity,
+            len: code.len(),
+        })
+    }
+}
+
+impl ValueKey {
+    fn of(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Char(c) => ValueKey::Char(*c),
+            Value::Closure { code, env, .. } => ValueKey::Closure {
+                position: Position::of(code)?,
+                env: Rc::as_ptr(env) as usize,
+            },
+            Value::Prim(prim) => ValueKey::Prim(prim.clone()),
+            Value::Native(native) => ValueKey::Ptr(native.addr()),
+            Value::Thunk(thunk) => ValueKey::Ptr(thunk.addr()),
+            Value::Continuation(state) => ValueKey::Ptr(Rc::as_ptr(state) as usize),
+        })
+    }
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Top;
+    use crate::parser::parse_prog;
+    use std::collections::VecDeque;
+    use std::num::NonZeroUsize;
+
+    fn app(func_idx: usize, range: Option<SourceRange>) -> Instr {
+        Instr::App {
+            func_idx: NonZeroUsize::new(func_idx).unwrap(),
+            arg_idx: NonZeroUsize::new(1).unwrap(),
+            range,
+        }
+    }
+
+    #[test]
+    fn test_synthetic_code() {
+        let env = Env::nil().push(Value::Char(0));
+        let state = |instr| State {
+            code: VecDeque::from(vec![instr]),
+            env: env.clone(),
+            dump: Vec::new(),
+        };
+        let mut detector = LoopDetector::new(LoopDetector::DEFAULT_CAPACITY);
+
+        // 位置の無い別々のコードは、形が同じでも同じ構成とみなさない
+        assert_eq!(detector.visit(&state(app(1, None)), 0), None);
+        assert_eq!(detector.visit(&state(app(2, None)), 1), None);
+
+        let prog = parse_prog("wWw").unwrap();
+        let Top::Abs(abs) = &prog.items[0] else {
+            unreachable!()
+        };
+        let range = abs.body[0].range;
+        assert_eq!(detector.visit(&state(app(1, Some(range))), 2), None);
+        assert_eq!(
+            detector.visit(&state(app(1, Some(range))), 3),
+            Some((2, Vec::new()))
+        );
+    }
+}
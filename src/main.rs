@@ -1,8 +1,28 @@
-use clap::Parser;
-use rusty_grass::parser::parse_prog;
-use rusty_grass::vm::VM;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use rusty_grass::church;
+use rusty_grass::compat::Compat;
+use rusty_grass::dap;
+use rusty_grass::debugger::{Command as DebugCommand, Debugger};
+use rusty_grass::fault::{Fault, FaultPlan};
+use rusty_grass::flight_recorder::{FlightRecorder, Rule};
+use rusty_grass::io::Replay;
+use rusty_grass::ir::Value;
+use rusty_grass::parser::{parse_prog, parse_prog_with};
+use rusty_grass::pipeline::Pipeline;
+use rusty_grass::pp::PrettyOptions;
+use rusty_grass::stack_trace::StackTrace;
+#[cfg(unix)]
+use rusty_grass::terminal;
+use rusty_grass::trace::{JsonTrace, TraceLevel};
+use rusty_grass::vm::{
+    CharMode, Dialect, InterruptHandle, RuntimeError, Status, Strategy, Suspended, VM,
+};
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, LineWriter};
+use std::panic::{self, AssertUnwindSafe};
+use thiserror::Error;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::prelude::*;
 
@@ -13,29 +33,755 @@ use tracing_subscriber::prelude::*;
     about = env!("CARGO_PKG_DESCRIPTION"),
 )]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long, default_value_t = false)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[arg(short, long, default_value_t = false, global = true)]
     verbose: bool,
 
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a program (the default when no command is given)
+    Run(RunArgs),
+    /// Resume a program from a snapshot written by --checkpoint-every
+    Resume(ResumeArgs),
+    /// Run programs in a pipeline, feeding each program's output to the next one's input
+    Pipe(PipeArgs),
+    /// Step through a program interactively
+    Debug(DebugArgs),
+    /// Serve the Debug Adapter Protocol over stdin and stdout
+    Dap,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
     #[arg(short, long, value_name = "program", default_value = None)]
     eval: Option<String>,
 
     #[arg(value_name = "path/to/progfile", default_value = None)]
     prog_file: Option<String>,
+
+    /// Primitives available in the initial environment
+    #[arg(long, value_enum, default_value_t = DialectArg::Standard)]
+    dialect: DialectArg,
+
+    /// Exit with the result of main: a character's byte, 0 for true, 1 for false, or a numeral
+    #[arg(long, default_value_t = false, conflicts_with = "checkpoint_every")]
+    exit_from_result: bool,
+
+    #[command(flatten)]
+    exec: ExecArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DialectArg {
+    /// Out, Succ, w and In, as in the specification
+    Standard,
+    /// Also Exit, Pred, Fail and Eof, placed after In
+    Extended,
+    /// Also Spawn, Send and Recv, placed after In
+    Concurrent,
+    /// Also CallCC, placed after In
+    Continuation,
+}
+
+#[derive(Args, Debug)]
+struct PipeArgs {
+    #[arg(value_name = "path/to/progfile", required = true, num_args = 1..)]
+    prog_files: Vec<String>,
+
+    /// Primitives available in the initial environment
+    #[arg(long, value_enum, default_value_t = DialectArg::Standard)]
+    dialect: DialectArg,
+
+    /// Bytes buffered between two programs
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = Pipeline::DEFAULT_CAPACITY as u64,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    buffer_size: u64,
+}
+
+#[derive(Args, Debug)]
+struct DebugArgs {
+    #[arg(value_name = "path/to/progfile")]
+    prog_file: String,
+
+    /// Primitives available in the initial environment
+    #[arg(long, value_enum, default_value_t = DialectArg::Standard)]
+    dialect: DialectArg,
+
+    /// Feed the program this file followed by EOF, instead of asking for input
+    #[arg(long, value_name = "path/to/file")]
+    input: Option<String>,
+
+    /// Treat characters as Unicode code points, read and written as UTF-8
+    #[arg(long, default_value_t = false)]
+    unicode: bool,
+
+    /// Make Succ wrap around at N in Unicode mode
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "unicode",
+        default_value_t = CharMode::UNICODE_MODULUS,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    modulus: u32,
+
+    /// Parse the program as another implementation does [default: spec]
+    #[arg(long, value_name = "profile", value_enum)]
+    compat: Option<CompatArg>,
+
+    #[command(flatten)]
+    pretty: PrettyArgs,
+}
+
+#[derive(Args, Debug)]
+struct ResumeArgs {
+    #[arg(value_name = "path/to/snapshot")]
+    snapshot: String,
+
+    #[command(flatten)]
+    exec: ExecArgs,
+}
+
+#[derive(Args, Debug)]
+struct ExecArgs {
+    /// Write a snapshot of the VM state every N steps
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_every: Option<u64>,
+
+    #[arg(long, value_name = "path/to/snapshot", default_value = "state.snap")]
+    checkpoint_file: String,
+
+    /// Record every byte read by In, and the steps at which EOF was read
+    #[arg(long, value_name = "path/to/session.log", conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Feed In from a recorded session instead of stdin
+    #[arg(long, value_name = "path/to/session.log")]
+    replay: Option<String>,
+
+    /// Stop with an error after N steps
+    #[arg(long, value_name = "N")]
+    max_steps: Option<u64>,
+
+    /// Stop with an error when the VM returns to the same configuration without doing I/O
+    #[arg(long, default_value_t = false)]
+    detect_loops: bool,
+
+    /// Number of recent transitions dumped when the program fails
+    #[arg(long, value_name = "N", default_value_t = FlightRecorder::DEFAULT_CAPACITY)]
+    flight_recorder: usize,
+
+    /// Write the flight recorder to a file instead of stderr
+    #[arg(long, value_name = "path/to/file")]
+    flight_log: Option<String>,
+
+    /// Write every transition to a file as JSON Lines
+    #[arg(long, value_name = "path/to/trace.jsonl")]
+    trace_json: Option<String>,
+
+    /// Transitions written by --trace-json
+    #[arg(long, value_name = "level", value_enum, requires = "trace_json", default_value_t = TraceLevelArg::All)]
+    trace_level: TraceLevelArg,
+
+    /// Treat characters as Unicode code points, read and written as UTF-8
+    #[arg(long, default_value_t = false)]
+    unicode: bool,
+
+    /// Make Succ wrap around at N in Unicode mode
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "unicode",
+        default_value_t = CharMode::UNICODE_MODULUS,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    modulus: u32,
+
+    /// Put the terminal into non-canonical, no-echo mode so that In reads each keypress
+    #[arg(long, default_value_t = false)]
+    raw_input: bool,
+
+    /// Evaluation strategy [default: eager]
+    #[arg(long, value_enum)]
+    strategy: Option<StrategyArg>,
+
+    /// Parse the program as another implementation does [default: spec]
+    #[arg(long, value_name = "profile", value_enum)]
+    compat: Option<CompatArg>,
+
+    /// Inject an I/O fault: eof@N ends input from the Nth In, byte@N=B makes the Nth In return byte B, out-error@N fails the Nth Out
+    #[arg(long, value_name = "fault")]
+    fault: Vec<Fault>,
+
+    #[command(flatten)]
+    pretty: PrettyArgs,
+}
+
+// 値を表示する時の設定。--trace-json と debug の print に使う
+#[derive(Args, Debug)]
+struct PrettyArgs {
+    /// Nesting depth of lists shown when printing values
+    #[arg(long, value_name = "N", default_value_t = PrettyOptions::default().max_depth)]
+    print_depth: usize,
+
+    /// Number of list elements shown when printing values
+    #[arg(long, value_name = "N", default_value_t = PrettyOptions::default().max_len)]
+    print_length: usize,
+
+    /// Do not number environment entries when printing values
+    #[arg(long, default_value_t = false)]
+    no_indices: bool,
+
+    /// Do not label closures with the position of the Abs that created them
+    #[arg(long, default_value_t = false)]
+    no_origin: bool,
+}
+
+// sysexits.h の EX_DATAERR, EX_NOINPUT, EX_SOFTWARE と、timeout(1) が時間切れで返す 124 に合わせる
+const EXIT_PARSE_ERROR: i32 = 65;
+const EXIT_NO_INPUT: i32 = 66;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_LIMIT_EXCEEDED: i32 = 124;
+const EXIT_INTERRUPTED: i32 = 130;
+
+// 実行の結果を終了ステータスにできなかった理由
+#[derive(Debug, Error)]
+enum ResultError {
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+    #[error("result cannot be used as an exit status: {0}")]
+    Unusable(String),
+    #[error("{0} is out of range for an exit status (0 to 255)")]
+    OutOfRange(u64),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum StrategyArg {
+    /// Call-by-value, as in the specification
+    Eager,
+    /// Call-by-need: applications are evaluated when their value is needed
+    Lazy,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CompatArg {
+    /// As in the specification
+    Spec,
+    /// Standard ML: US-ASCII only
+    Sml,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TraceLevelArg {
+    /// Transitions that read or write a character
+    Io,
+    /// Every transition except building closures
+    Calls,
+    /// Every transition
+    All,
+}
+
+impl From<DialectArg> for Dialect {
+    fn from(arg: DialectArg) -> Self {
+        match arg {
+            DialectArg::Standard => Dialect::Standard,
+            DialectArg::Extended => Dialect::Extended,
+            DialectArg::Concurrent => Dialect::Concurrent,
+            DialectArg::Continuation => Dialect::Continuation,
+        }
+    }
+}
+
+impl From<CompatArg> for Compat {
+    fn from(arg: CompatArg) -> Self {
+        match arg {
+            CompatArg::Spec => Compat::Spec,
+            CompatArg::Sml => Compat::Sml,
+        }
+    }
+}
+
+impl From<&PrettyArgs> for PrettyOptions {
+    fn from(args: &PrettyArgs) -> Self {
+        PrettyOptions {
+            max_depth: args.print_depth,
+            max_len: args.print_length,
+            show_indices: !args.no_indices,
+            show_origin: !args.no_origin,
+        }
+    }
+}
+
+impl From<TraceLevelArg> for TraceLevel {
+    fn from(arg: TraceLevelArg) -> Self {
+        match arg {
+            TraceLevelArg::Io => TraceLevel::Io,
+            TraceLevelArg::Calls => TraceLevel::Calls,
+            TraceLevelArg::All => TraceLevel::All,
+        }
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    if args.verbose {
+    if cli.verbose {
         init_trace();
     }
 
-    let prog_source = prog_source(args.eval, args.prog_file.as_deref());
-    let prog = parse_prog(&prog_source).expect("failed to parse program");
+    match cli.command.unwrap_or(Command::Run(cli.run)) {
+        Command::Run(run) => {
+            let prog_source = prog_source(run.eval, run.prog_file.as_deref());
+            let compat = run.exec.compat.map_or(Compat::Spec, Compat::from);
+            let prog = match parse_prog_with(&prog_source, compat.syntax()) {
+                Ok(prog) => prog,
+                Err(err) => {
+                    eprintln!("failed to parse program: {}", err);
+                    exit(EXIT_PARSE_ERROR);
+                }
+            };
+
+            let mut vm = VM::new(&prog);
+            vm.set_dialect(run.dialect.into());
+            execute(vm, &run.exec, run.exit_from_result);
+        }
+        Command::Resume(resume) => {
+            // 文字の扱いと評価戦略はスナップショットから戻す
+            if resume.exec.unicode || resume.exec.strategy.is_some() {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--unicode and --strategy are restored from the snapshot",
+                    )
+                    .exit();
+            }
+            // 互換性の設定は構文にしか効かないので、解析済みの状態には使えない
+            if resume.exec.compat.is_some() {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--compat only applies when parsing a program",
+                    )
+                    .exit();
+            }
+            let f = open(&resume.snapshot);
+            let vm = match VM::load_snapshot(&mut BufReader::new(f)) {
+                Ok(vm) => vm,
+                Err(err) => {
+                    eprintln!("failed to load {}: {}", resume.snapshot, err);
+                    exit(EXIT_PARSE_ERROR);
+                }
+            };
+
+            execute(vm, &resume.exec, false);
+        }
+        Command::Pipe(pipe) => run_pipeline(&pipe),
+        Command::Debug(debug) => run_debugger(&debug),
+        Command::Dap => {
+            if let Err(err) = dap::serve(BufReader::new(std::io::stdin()), std::io::stdout().lock())
+            {
+                eprintln!("debug adapter failed: {}", err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+        }
+    }
+}
+
+fn run_debugger(args: &DebugArgs) {
+    let compat = args.compat.map_or(Compat::Spec, Compat::from);
+    let source = prog_source(None, Some(&args.prog_file));
+    let prog = match parse_prog_with(&source, compat.syntax()) {
+        Ok(prog) => prog,
+        Err(err) => {
+            eprintln!("failed to parse program: {}", err);
+            exit(EXIT_PARSE_ERROR);
+        }
+    };
+    // 標準入力はデバッガのコマンドに使うので、プログラムの入力は input コマンドで渡す
+    let mut vm = VM::resumable(&prog);
+    vm.set_dialect(args.dialect.into());
+    if args.unicode {
+        vm.set_char_mode(CharMode::Unicode {
+            modulus: args.modulus,
+        });
+    }
+    interrupt_on_ctrl_c(vm.interrupt_handle());
+
+    let mut debugger = Debugger::new(vm);
+    debugger.set_pretty_options((&args.pretty).into());
+    if let Some(path) = &args.input {
+        let mut input = Vec::new();
+        open(path)
+            .read_to_end(&mut input)
+            .expect("failed to read input file");
+        debugger.set_input(&input);
+    }
+    let mut stdout = std::io::stdout();
+    let mut last = None;
+    loop {
+        print!("(grass) ");
+        stdout.flush().ok();
+        let mut line = String::new();
+        if std::io::stdin()
+            .read_line(&mut line)
+            .expect("failed to read command")
+            == 0
+        {
+            println!();
+            break;
+        }
+        // 空行は直前のコマンドを繰り返す
+        let command: DebugCommand = match line.trim_end_matches(['\r', '\n']) {
+            "" => match last.clone() {
+                Some(command) => command,
+                None => continue,
+            },
+            line => match line.parse() {
+                Ok(command) => command,
+                Err(err) => {
+                    println!("{}", err);
+                    continue;
+                }
+            },
+        };
+        if command == DebugCommand::Quit {
+            break;
+        }
+        debugger
+            .execute(command.clone(), &mut stdout)
+            .expect("failed to write to stdout");
+        last = Some(command);
+    }
+}
+
+fn run_pipeline(args: &PipeArgs) {
+    let mut vms = Vec::with_capacity(args.prog_files.len());
+    for (index, path) in args.prog_files.iter().enumerate() {
+        let prog = match parse_prog(&prog_source(None, Some(path))) {
+            Ok(prog) => prog,
+            Err(err) => {
+                eprintln!("failed to parse {}: {}", path, err);
+                exit(EXIT_PARSE_ERROR);
+            }
+        };
+        // 先頭だけが標準入力から読み、残りは前段の出力を受け取る
+        let mut vm = match index {
+            0 => VM::new(&prog),
+            _ => VM::resumable(&prog),
+        };
+        vm.set_dialect(args.dialect.into());
+        vms.push(vm);
+    }
+
+    let mut pipeline = Pipeline::new(vms, args.buffer_size as usize);
+    cancel_on_ctrl_c(pipeline.interrupt_handle());
+    let result = pipeline.run();
+
+    std::io::stdout().flush().ok();
+    match result {
+        Ok(Status::Exited(code)) => match exit_status(code) {
+            Ok(code) => exit(code),
+            Err(err) => {
+                eprintln!("{}", err);
+                exit(EXIT_RUNTIME_ERROR);
+            }
+        },
+        Ok(Status::Halted) => exit(0),
+        Ok(Status::Suspended(suspended)) => {
+            eprintln!(
+                "runtime error occurred: {}",
+                RuntimeError::Suspended(suspended)
+            );
+            exit(EXIT_RUNTIME_ERROR);
+        }
+        Err(err) => {
+            let path = &args.prog_files[err.stage];
+            match err.error.stack_trace() {
+                Some(trace) => eprintln!(
+                    "runtime error occurred in {}: {}\n{}",
+                    path, err.error, trace
+                ),
+                None => eprintln!("runtime error occurred in {}: {}", path, err.error),
+            }
+            exit(error_status(&err.error));
+        }
+    }
+}
+
+fn execute(mut vm: VM, exec: &ExecArgs, exit_from_result: bool) {
+    if let Some(path) = &exec.record {
+        let f = File::create(path).expect("failed to create session log");
+        vm.record_input(Box::new(LineWriter::new(f)));
+    }
+    if let Some(path) = &exec.replay {
+        let f = open(path);
+        let replay = Replay::read(BufReader::new(f)).expect("failed to read session log");
+        vm.replay_input(replay);
+    }
+
+    if let Some(path) = &exec.trace_json {
+        let f = File::create(path).expect("failed to create trace file");
+        let mut trace = JsonTrace::new(Box::new(LineWriter::new(f)), exec.trace_level.into());
+        trace.set_pretty_options((&exec.pretty).into());
+        vm.add_observer(Box::new(trace));
+    }
+
+    vm.set_step_limit(exec.max_steps);
+    vm.set_flight_recorder(exec.flight_recorder);
+    vm.set_loop_detection(exec.detect_loops);
+    if !exec.fault.is_empty() {
+        vm.set_fault_plan(FaultPlan::new(exec.fault.iter().copied()));
+    }
+    if let Some(strategy) = exec.strategy {
+        vm.set_strategy(match strategy {
+            StrategyArg::Eager => Strategy::CallByValue,
+            StrategyArg::Lazy => Strategy::CallByNeed,
+        });
+    }
+    if exec.unicode {
+        vm.set_char_mode(CharMode::Unicode {
+            modulus: exec.modulus,
+        });
+    }
+
+    cancel_on_ctrl_c(vm.interrupt_handle());
+    if exec.raw_input {
+        enable_raw_input();
+    }
+
+    // 正常に終わった時の終了ステータス
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if exit_from_result {
+            return match run_main(&mut vm) {
+                Ok(value) => exit_code(&mut vm, value),
+                Err(RuntimeError::Exited(code)) => exit_status(code),
+                Err(err) => Err(err.into()),
+            };
+        }
+        let status = match exec.checkpoint_every {
+            None => vm.run(),
+            Some(interval) => loop {
+                match vm.run_for(interval) {
+                    Ok(Status::Suspended(Suspended::OutOfSteps)) => {
+                        if let Err(err) = checkpoint(&vm, &exec.checkpoint_file) {
+                            eprintln!("failed to write snapshot {}: {}", exec.checkpoint_file, err);
+                            exit(EXIT_RUNTIME_ERROR);
+                        }
+                    }
+                    result => break result,
+                }
+            },
+        };
+        match status? {
+            Status::Exited(code) => exit_status(code),
+            _ => Ok(0),
+        }
+    }));
+    let result = match result {
+        Ok(result) => result,
+        Err(payload) => {
+            dump_flight_recorder(&vm, exec);
+            restore_terminal();
+            panic::resume_unwind(payload);
+        }
+    };
+    if let Err(ResultError::Runtime(_)) = result {
+        dump_flight_recorder(&vm, exec);
+    }
+
+    std::io::stdout().flush().ok();
+    match result {
+        Ok(code) => exit(code),
+        Err(ResultError::Runtime(RuntimeError::Interrupted)) => {
+            eprintln!("\n{}", interrupted(&vm));
+            exit(EXIT_INTERRUPTED);
+        }
+        Err(ResultError::Runtime(err)) => {
+            match err.stack_trace() {
+                Some(trace) => eprintln!("runtime error occurred: {}\n{}", err, trace),
+                None => eprintln!("runtime error occurred: {}", err),
+            }
+            exit(error_status(&err));
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(EXIT_RUNTIME_ERROR);
+        }
+    }
+}
+
+// main を自分自身に一度だけ適用した結果を返す
+fn run_main(vm: &mut VM) -> Result<Value, RuntimeError> {
+    let main = vm.definitions()?.pop().ok_or(RuntimeError::IllegalState)?;
+    vm.apply(main.clone(), main)
+}
+
+// 真偽値と 0 はどちらも λx y. y なので、真偽値として扱う。
+// OS は終了ステータスの下位 8 ビットしか伝えないため、255 を超える値は切り詰めずに拒む
+fn exit_code(vm: &mut VM, value: Value) -> Result<i32, ResultError> {
+    let code = if let Value::Char(c) = value {
+        c.into()
+    } else if let Some(b) = church::decode_bool(vm, &value)? {
+        return Ok(if b { 0 } else { 1 });
+    } else if let Some(n) = church::decode_numeral(vm, &value)? {
+        n as u64
+    } else {
+        return Err(ResultError::Unusable(format!("{:?}", value)));
+    };
+    match u8::try_from(code) {
+        Ok(code) => Ok(code.into()),
+        Err(_) => Err(ResultError::OutOfRange(code)),
+    }
+}
+
+// Exit の引数も、main の結果と同じく 255 を超えれば拒む
+fn exit_status(code: u32) -> Result<i32, ResultError> {
+    match u8::try_from(code) {
+        Ok(code) => Ok(code.into()),
+        Err(_) => Err(ResultError::OutOfRange(code.into())),
+    }
+}
+
+// 止めた時点の手数と呼び出しの深さに、次に実行する App の位置を添える
+fn interrupted(vm: &VM) -> String {
+    let site = match Rule::of(vm.state()) {
+        Rule::App { range, .. } => range,
+        _ => None,
+    };
+    let trace = StackTrace::capture(vm.state(), site);
+    let mut message = format!(
+        "interrupted at step {} (call depth {})",
+        vm.steps(),
+        vm.state().dump.len()
+    );
+    if !trace.frames.is_empty() {
+        message.push_str(&format!("\n{}", trace));
+    }
+    message
+}
+
+fn error_status(err: &RuntimeError) -> i32 {
+    match err {
+        RuntimeError::Interrupted => EXIT_INTERRUPTED,
+        RuntimeError::StepLimitExceeded(_) => EXIT_LIMIT_EXCEEDED,
+        _ => EXIT_RUNTIME_ERROR,
+    }
+}
+
+fn dump_flight_recorder(vm: &VM, exec: &ExecArgs) {
+    let recorder = vm.flight_recorder();
+    if recorder.transitions().next().is_none() {
+        return;
+    }
+    std::io::stdout().flush().ok();
+    match &exec.flight_log {
+        Some(path) => {
+            let mut f = File::create(path).expect("failed to create flight log");
+            writeln!(f, "{}", recorder).expect("failed to write flight log");
+        }
+        None => eprintln!("{}", recorder),
+    }
+}
+
+// 1回目の Ctrl-C で VM を止める。In で入力待ちのまま止まらない場合に備え、2回目で強制終了する
+#[cfg(unix)]
+fn cancel_on_ctrl_c(handle: InterruptHandle) {
+    use signal_hook::consts::SIGINT;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGINT]).expect("failed to install signal handler");
+    std::thread::spawn(move || {
+        for (count, _) in signals.forever().enumerate() {
+            if count == 0 {
+                handle.cancel();
+            } else {
+                exit(EXIT_INTERRUPTED);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn cancel_on_ctrl_c(_handle: InterruptHandle) {}
+
+// デバッガでは Ctrl-C の度にプログラムを止めてプロンプトに戻る。入力はデバッガが与えるので待ったままにはならない
+#[cfg(unix)]
+fn interrupt_on_ctrl_c(handle: InterruptHandle) {
+    use signal_hook::consts::SIGINT;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGINT]).expect("failed to install signal handler");
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            handle.cancel();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn interrupt_on_ctrl_c(_handle: InterruptHandle) {}
+
+#[cfg(unix)]
+fn enable_raw_input() {
+    use std::os::fd::AsRawFd;
+
+    if let Err(err) = terminal::enable_raw_mode(std::io::stdin().as_raw_fd()) {
+        eprintln!("failed to enable raw input: {}", err);
+        exit(EXIT_RUNTIME_ERROR);
+    }
+}
+
+#[cfg(not(unix))]
+fn enable_raw_input() {
+    eprintln!("--raw-input is only supported on Unix");
+    exit(EXIT_RUNTIME_ERROR);
+}
+
+#[cfg(unix)]
+fn restore_terminal() {
+    terminal::restore().ok();
+}
+
+#[cfg(not(unix))]
+fn restore_terminal() {}
 
-    let mut vm = VM::new(&prog);
-    vm.run().expect("runtime error occurred");
+// --raw-input で変えた端末の設定を戻してから終了する
+fn exit(code: i32) -> ! {
+    restore_terminal();
+    std::process::exit(code)
+}
+
+// タスクや継続、評価待ちのサンクが残っていて保存できなければ、直前のスナップショットを残してエラーを返す
+fn checkpoint(vm: &VM, path: &str) -> std::io::Result<()> {
+    // 再開時に出力が欠けないよう、スナップショットより先に出力を確定させる
+    std::io::stdout().flush()?;
+
+    // 書き込み途中で落ちても直前のスナップショットが残るよう、一時ファイル経由で置き換える
+    let tmp_path = format!("{}.tmp", path);
+    let result = File::create(&tmp_path).and_then(|f| {
+        let mut w = BufWriter::new(f);
+        vm.save_snapshot(&mut w)?;
+        w.flush()
+    });
+    match result {
+        Ok(()) => std::fs::rename(&tmp_path, path),
+        Err(err) => {
+            std::fs::remove_file(&tmp_path).ok();
+            Err(err)
+        }
+    }
 }
 
 fn init_trace() {
@@ -61,12 +807,95 @@ fn prog_source(eval: Option<String>, prog_file: Option<&str>) -> String {
     if let Some(source) = eval {
         source
     } else if let Some(file_path) = prog_file {
-        let mut f = File::open(file_path).expect("program file not found");
+        let mut f = open(file_path);
         let mut prog_source = String::new();
         f.read_to_string(&mut prog_source)
             .expect("failed to read program file");
         prog_source
     } else {
-        panic!("either --eval or program file must be provided");
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "either --eval or program file must be provided",
+            )
+            .exit();
+    }
+}
+
+// 開けなければ EX_NOINPUT で終了する
+fn open(path: &str) -> File {
+    File::open(path).unwrap_or_else(|err| {
+        eprintln!("failed to open {}: {}", path, err);
+        exit(EXIT_NO_INPUT);
+    })
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        // λx. x
+        let prog = parse_prog("w").unwrap();
+        let mut vm = VM::new(&prog);
+        let mut code = |value| exit_code(&mut vm, value);
+
+        assert_eq!(code(Value::Char(b'a'.into())).unwrap(), 97);
+        assert_eq!(code(church::boolean(true)).unwrap(), 0);
+        assert_eq!(code(church::boolean(false)).unwrap(), 1);
+        // 0 は偽と同じ項なので 1 になる
+        assert_eq!(code(church::numeral(0)).unwrap(), 1);
+        assert_eq!(code(church::numeral(3)).unwrap(), 3);
+        assert_eq!(code(church::numeral(255)).unwrap(), 255);
+
+        assert!(matches!(
+            code(church::numeral(256)),
+            Err(ResultError::OutOfRange(256))
+        ));
+        assert!(matches!(
+            code(Value::Char(0x3042)),
+            Err(ResultError::OutOfRange(0x3042))
+        ));
+        let pair = church::pair(Value::Char(0), Value::Char(0));
+        assert!(matches!(code(pair), Err(ResultError::Unusable(_))));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(exit_status(0).unwrap(), 0);
+        assert_eq!(exit_status(255).unwrap(), 255);
+        assert!(matches!(
+            exit_status(256),
+            Err(ResultError::OutOfRange(256))
+        ));
+        assert!(matches!(
+            exit_status(0x3042),
+            Err(ResultError::OutOfRange(0x3042))
+        ));
+    }
+
+    #[test]
+    fn test_interrupted() {
+        // λx. x x。自分自身を呼び続ける
+        let prog = parse_prog("wWw").unwrap();
+        let mut vm = VM::new(&prog);
+        vm.set_output(Box::new(std::io::sink()));
+        assert!(vm.run_for(10).is_ok());
+        vm.interrupt_handle().cancel();
+        assert!(matches!(vm.run(), Err(RuntimeError::Interrupted)));
+        assert_eq!(
+            interrupted(&vm),
+            "interrupted at step 10 (call depth 7)\n   0: fn@1:1 at 1:2 (repeated 7 times)\n   1: <main> at 1:2"
+        );
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(error_status(&RuntimeError::Interrupted), 130);
+        assert_eq!(error_status(&RuntimeError::StepLimitExceeded(10)), 124);
+        assert_eq!(error_status(&RuntimeError::IllegalState), 70);
     }
 }
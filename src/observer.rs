@@ -0,0 +1,55 @@
+use crate::ast::SourceRange;
+use crate::flight_recorder::Transition;
+use crate::io::IoEvent;
+use crate::ir::{Prim, Value};
+use std::cell::RefCell;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+
+// VM の実行を外から観測するためのコールバック。
+// 必要なものだけ実装すればよく、観測者が居なければ呼び出しの費用もかからない
+pub trait Observer {
+    // func を arg に適用する直前。site は App の位置で、main の自己適用では None
+    fn on_app(&mut self, _func: &Value, _arg: &Value, _site: Option<SourceRange>) {}
+
+    // Abs を評価してクロージャを作った直後
+    fn on_abs(&mut self, _arity: NonZeroUsize, _closure: &Value) {}
+
+    // 関数本体の実行を終えて呼び出し元に value を返した直後
+    fn on_return(&mut self, _value: &Value) {}
+
+    // プリミティブを arg に適用して result を得た直後
+    fn on_prim(&mut self, _prim: &Prim, _arg: &Value, _result: &Value) {}
+
+    fn on_io(&mut self, _event: IoEvent) {}
+
+    // 遷移を一つ終えた直後。エラーになった遷移も含む
+    fn on_transition(&mut self, _transition: &Transition) {}
+}
+
+// 実行後に結果を取り出せるよう、共有した観測者もそのまま登録できるようにする
+impl<T: Observer> Observer for Rc<RefCell<T>> {
+    fn on_app(&mut self, func: &Value, arg: &Value, site: Option<SourceRange>) {
+        self.borrow_mut().on_app(func, arg, site);
+    }
+
+    fn on_abs(&mut self, arity: NonZeroUsize, closure: &Value) {
+        self.borrow_mut().on_abs(arity, closure);
+    }
+
+    fn on_return(&mut self, value: &Value) {
+        self.borrow_mut().on_return(value);
+    }
+
+    fn on_prim(&mut self, prim: &Prim, arg: &Value, result: &Value) {
+        self.borrow_mut().on_prim(prim, arg, result);
+    }
+
+    fn on_io(&mut self, event: IoEvent) {
+        self.borrow_mut().on_io(event);
+    }
+
+    fn on_transition(&mut self, transition: &Transition) {
+        self.borrow_mut().on_transition(transition);
+    }
+}
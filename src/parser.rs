@@ -6,9 +6,37 @@ use combine::{
 };
 use std::iter;
 
+// 受け付ける構文の違い。既定値は仕様通り
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Syntax {
+    // 全角の "ｗ", "Ｗ", "ｖ" を半角と同じものとして扱う
+    pub fullwidth: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self { fullwidth: true }
+    }
+}
+
+impl Syntax {
+    // US-ASCII の文字だけを受け付ける構文
+    pub fn ascii() -> Self {
+        Self { fullwidth: false }
+    }
+
+    fn letters(self, ascii: &'static str, fullwidth: &'static str) -> &'static str {
+        if self.fullwidth { fullwidth } else { ascii }
+    }
+}
+
 pub fn parse_prog(input: &str) -> Result<ast::Prog, StringStreamError> {
+    parse_prog_with(input, Syntax::default())
+}
+
+pub fn parse_prog_with(input: &str, syntax: Syntax) -> Result<ast::Prog, StringStreamError> {
     let stream = PositionStream::new(input);
-    match prog().parse(stream) {
+    match prog(syntax).parse(stream) {
         Ok((prog, _remaining_input)) => Ok(prog),
         Err(err) => Err(err),
     }
@@ -16,45 +44,53 @@ pub fn parse_prog(input: &str) -> Result<ast::Prog, StringStreamError> {
 
 // ========================================================================== //
 
-fn white_space<Input>() -> impl Parser<Input, Output = ()>
+fn white_space<Input>(syntax: Syntax) -> impl Parser<Input, Output = ()>
 where
     Input: Stream<Token = char>,
 {
-    skip_many(none_of("wWvｗＷｖ".chars()))
+    skip_many(none_of(syntax.letters("wWv", "wWvｗＷｖ").chars()))
 }
 
-fn head_white_space<Input>() -> impl Parser<Input, Output = ()>
+fn head_white_space<Input>(syntax: Syntax) -> impl Parser<Input, Output = ()>
 where
     Input: Stream<Token = char>,
 {
-    skip_many(none_of("wｗ".chars()))
+    skip_many(none_of(syntax.letters("w", "wｗ").chars()))
 }
 
-fn char_w<Input>() -> impl Parser<Input, Output = char>
+fn char_w<Input>(syntax: Syntax) -> impl Parser<Input, Output = char>
 where
     Input: Stream<Token = char>,
 {
-    one_of("wｗ".chars()).skip(white_space()).map(|_| 'w')
+    one_of(syntax.letters("w", "wｗ").chars())
+        .skip(white_space(syntax))
+        .map(|_| 'w')
 }
 
 #[allow(non_snake_case)]
-fn char_W<Input>() -> impl Parser<Input, Output = char>
+fn char_W<Input>(syntax: Syntax) -> impl Parser<Input, Output = char>
 where
     Input: Stream<Token = char>,
 {
-    one_of("WＷ".chars()).skip(white_space()).map(|_| 'W')
+    one_of(syntax.letters("W", "WＷ").chars())
+        .skip(white_space(syntax))
+        .map(|_| 'W')
 }
 
-fn char_v<Input>() -> impl Parser<Input, Output = char>
+fn char_v<Input>(syntax: Syntax) -> impl Parser<Input, Output = char>
 where
     Input: Stream<Token = char>,
 {
-    one_of("vｖ".chars()).skip(white_space()).map(|_| 'v')
+    one_of(syntax.letters("v", "vｖ").chars())
+        .skip(white_space(syntax))
+        .map(|_| 'v')
 }
 
-fn app<'a>() -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::App> {
-    let func_idx = many1::<Vec<_>, _, _>(char_W()).map(|ws| ws.len());
-    let arg_idx = many1::<Vec<_>, _, _>(char_w()).map(|ws| ws.len());
+fn app<'a>(
+    syntax: Syntax,
+) -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::App> {
+    let func_idx = many1::<Vec<_>, _, _>(char_W(syntax)).map(|ws| ws.len());
+    let arg_idx = many1::<Vec<_>, _, _>(char_w(syntax)).map(|ws| ws.len());
 
     (position(), func_idx, arg_idx, position()).map(|(start_pos, func_idx, arg_idx, end_pos)| {
         ast::App {
@@ -68,9 +104,11 @@ fn app<'a>() -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = as
     })
 }
 
-fn abs<'a>() -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::Abs> {
-    let arity = many1::<Vec<_>, _, _>(char_w()).map(|ws| ws.len());
-    let body = many(app());
+fn abs<'a>(
+    syntax: Syntax,
+) -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::Abs> {
+    let arity = many1::<Vec<_>, _, _>(char_w(syntax)).map(|ws| ws.len());
+    let body = many(app(syntax));
 
     (position(), arity, body, position()).map(|(start_pos, arity, body, end_pos)| ast::Abs {
         arity,
@@ -82,13 +120,17 @@ fn abs<'a>() -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = as
     })
 }
 
-fn prog<'a>() -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::Prog> {
-    let head = abs().map(ast::Top::Abs);
+fn prog<'a>(
+    syntax: Syntax,
+) -> impl Parser<PositionStream<&'a str, SourcePosition>, Output = ast::Prog> {
+    let head = abs(syntax).map(ast::Top::Abs);
 
-    let top = abs().map(ast::Top::Abs).or(app().map(ast::Top::App));
-    let tail = many::<Vec<_>, _, _>(optional(char_v()).with(top));
+    let top = abs(syntax)
+        .map(ast::Top::Abs)
+        .or(app(syntax).map(ast::Top::App));
+    let tail = many::<Vec<_>, _, _>(optional(char_v(syntax)).with(top));
 
-    (head_white_space(), head, tail).map(|(_, head, tail)| {
+    (head_white_space(syntax), head, tail).map(|(_, head, tail)| {
         // 先頭が head, それに tail が続く Vec<ast::Top> を作る
         let items = iter::once(head).chain(tail.iter().cloned()).collect();
         ast::Prog { items }
@@ -104,7 +146,7 @@ mod tests {
     #[test]
     fn test_app() {
         let input = "WWWwwww";
-        let result = app().parse(PositionStream::new(input));
+        let result = app(Syntax::default()).parse(PositionStream::new(input));
         assert!(result.is_ok());
         let (app, _) = result.unwrap();
         assert_eq!(
@@ -123,7 +165,7 @@ mod tests {
     #[test]
     fn test_abs() {
         let input = "wwwwwWWwwwwWwww";
-        let result = abs().parse(PositionStream::new(input));
+        let result = abs(Syntax::default()).parse(PositionStream::new(input));
         assert!(result.is_ok());
         let (abs, _) = result.unwrap();
         assert_eq!(
@@ -171,7 +213,7 @@ mod tests {
     #[test]
     fn test_prog() {
         let input = "wWWwwwvWWWWwwwwwWWwvwwWwwWWWwwwwwWWWWWwwwwww";
-        let result = prog().parse(PositionStream::new(input));
+        let result = prog(Syntax::default()).parse(PositionStream::new(input));
         assert!(result.is_ok());
         let (prog, _) = result.unwrap();
         assert_eq!(prog.items.len(), 4);
@@ -285,4 +327,14 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_syntax() {
+        let input = "ｗＷＷｗ v wWWw";
+        let prog = parse_prog(input).unwrap();
+        assert_eq!(prog.items.len(), 2);
+        // 全角の文字は読み飛ばされる
+        let prog = parse_prog_with(input, Syntax::ascii()).unwrap();
+        assert_eq!(prog.items.len(), 1);
+    }
 }
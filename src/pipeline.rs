@@ -0,0 +1,245 @@
+use crate::vm::{InterruptHandle, RuntimeError, Status, Suspended, VM};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+use thiserror::Error;
+
+// 前段の Out を後段の In につなぎ、複数の VM を一つのスレッドで交互に動かす
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    // pipes[i] は stages[i] の出力を stages[i + 1] に渡す
+    pipes: Vec<Pipe>,
+    capacity: usize,
+}
+
+struct Stage {
+    vm: VM,
+    // 終了した時の状態
+    status: Option<Status>,
+}
+
+// 段と段の間のバッファ
+#[derive(Clone, Default)]
+struct Pipe(Rc<RefCell<PipeState>>);
+
+#[derive(Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    // 読み手が終了した
+    closed: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("stage {stage}: {error}")]
+pub struct PipelineError {
+    pub stage: usize,
+    pub error: RuntimeError,
+}
+
+impl Pipeline {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    // 一つの段に続けて実行させるステップ数
+    const QUANTUM: usize = 1024;
+
+    // 先頭の VM の入力と末尾の VM の出力はそのまま使う。
+    // 2段目以降の VM は VM::resumable() などで、ホストから入力を受け取るように作っておく
+    pub fn new(vms: Vec<VM>, capacity: usize) -> Self {
+        assert!(!vms.is_empty(), "pipeline needs at least one program");
+
+        let mut stages: Vec<Stage> = vms
+            .into_iter()
+            .map(|vm| Stage { vm, status: None })
+            .collect();
+        let pipes: Vec<Pipe> = (1..stages.len()).map(|_| Pipe::default()).collect();
+        let interrupt = stages[0].vm.interrupt_handle();
+        for (stage, pipe) in stages.iter_mut().zip(&pipes) {
+            stage.vm.set_output(Box::new(pipe.clone()));
+        }
+        for stage in &mut stages[1..] {
+            stage.vm.set_interrupt_handle(interrupt.clone());
+        }
+
+        Self {
+            stages,
+            pipes,
+            capacity,
+        }
+    }
+
+    pub fn stage_mut(&mut self, index: usize) -> &mut VM {
+        &mut self.stages[index].vm
+    }
+
+    // 全ての段を止めるハンドル
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.stages[0].vm.interrupt_handle()
+    }
+
+    // 末尾の段が終わるまで実行し、その状態を返す。末尾の段が終われば出力を読む者は居ないので、
+    // 前段が残っていても止める。先頭の段がホストからの入力を待っている時は Suspended::NeedInput を返す
+    pub fn run(&mut self) -> Result<Status, PipelineError> {
+        loop {
+            let mut progressed = false;
+            for index in 0..self.stages.len() {
+                if self.stages[index].status.is_none() {
+                    progressed |= self.run_stage(index)?;
+                }
+            }
+
+            if let Some(status) = self.stages.last().unwrap().status {
+                return Ok(status);
+            }
+            if !progressed {
+                return Ok(Status::Suspended(Suspended::NeedInput));
+            }
+        }
+    }
+
+    // 一つの段を少しだけ進める。進んだかどうかを返す
+    fn run_stage(&mut self, index: usize) -> Result<bool, PipelineError> {
+        let mut progressed = false;
+        for _ in 0..Self::QUANTUM {
+            // 出力先が一杯なら後段が読むまで待つ
+            if self.is_full(index) {
+                break;
+            }
+            match self.stages[index].vm.run_for(1) {
+                Ok(Status::Suspended(Suspended::OutOfSteps)) => progressed = true,
+                Ok(Status::Suspended(Suspended::NeedInput)) => {
+                    if !self.transfer(index) {
+                        break;
+                    }
+                    progressed = true;
+                }
+                Ok(status) => {
+                    self.finish(index, status);
+                    return Ok(true);
+                }
+                // 読み手の居なくなった段は、SIGPIPE を受けたプロセスのように止める
+                Err(RuntimeError::Io(err)) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    self.finish(index, Status::Halted);
+                    return Ok(true);
+                }
+                Err(error) => {
+                    return Err(PipelineError {
+                        stage: index,
+                        error,
+                    });
+                }
+            }
+        }
+        Ok(progressed)
+    }
+
+    fn is_full(&self, index: usize) -> bool {
+        match self.pipes.get(index) {
+            Some(pipe) => pipe.0.borrow().bytes.len() >= self.capacity,
+            None => false,
+        }
+    }
+
+    // 前段の出力を入力として渡す。前段が終わっていれば EOF を渡す
+    fn transfer(&mut self, index: usize) -> bool {
+        if index == 0 {
+            return false;
+        }
+        let bytes: Vec<u8> = self.pipes[index - 1]
+            .0
+            .borrow_mut()
+            .bytes
+            .drain(..)
+            .collect();
+        if !bytes.is_empty() {
+            self.stages[index].vm.feed(&bytes);
+            true
+        } else if self.stages[index - 1].status.is_some() {
+            self.stages[index].vm.feed_eof();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn finish(&mut self, index: usize, status: Status) {
+        self.stages[index].status = Some(status);
+        if index > 0 {
+            let mut pipe = self.pipes[index - 1].0.borrow_mut();
+            pipe.bytes.clear();
+            pipe.closed = true;
+        }
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        pipe.bytes.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::SharedBuffer;
+    use crate::parser::parse_prog;
+
+    #[test]
+    fn test_pipeline() {
+        // λx. Out (Succ (In x))
+        let succ = parse_prog("wWWWWWwWWWWwWWWWw").unwrap();
+        let output = SharedBuffer::default();
+        let mut last = VM::resumable(&succ);
+        last.set_output(Box::new(output.clone()));
+
+        let mut pipeline = Pipeline::new(vec![VM::resumable(&succ), last], 1);
+        assert_eq!(
+            pipeline.run().unwrap(),
+            Status::Suspended(Suspended::NeedInput)
+        );
+        pipeline.stage_mut(0).feed(b"a");
+        assert_eq!(pipeline.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"c");
+    }
+
+    #[test]
+    fn test_eof() {
+        // λx. Out w
+        let out_w = parse_prog("wWWwwww").unwrap();
+        // λx. Out (In (In x))
+        let echo = parse_prog("wWWWWWwWWWWWWwWWWWw").unwrap();
+        let output = SharedBuffer::default();
+        let mut last = VM::resumable(&echo);
+        last.set_output(Box::new(output.clone()));
+
+        // 前段が終わると後段の2回目の In が EOF になる
+        let mut pipeline = Pipeline::new(vec![VM::resumable(&out_w), last], 1);
+        assert_eq!(pipeline.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"w");
+    }
+
+    #[test]
+    fn test_last_stage_finished() {
+        // λx. x x は終わらないが、末尾の λx. Out w が終われば止まる
+        let looping = parse_prog("wWw").unwrap();
+        let out_w = parse_prog("wWWwwww").unwrap();
+        let output = SharedBuffer::default();
+        let mut last = VM::resumable(&out_w);
+        last.set_output(Box::new(output.clone()));
+
+        let mut pipeline = Pipeline::new(vec![VM::resumable(&looping), last], 1);
+        assert_eq!(pipeline.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"w");
+    }
+}
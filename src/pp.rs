@@ -1,109 +1,171 @@
+use crate::ast::SourceRange;
 use crate::ir::*;
 use std::fmt::Debug;
+use std::num::NonZeroUsize;
 use std::rc::Rc;
 
-pub struct PP<'a, T>(pub &'a T);
+pub struct PP<'a, T>(pub &'a T, pub PrettyOptions);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrettyOptions {
+    // これより深く入れ子になったリストは省略表示する
+    pub max_depth: usize,
+    // リストは高々この要素数まで表示し、残りがあれば省略表示する
+    pub max_len: usize,
+    // 環境の各要素に de Bruijn インデックスを添える
+    pub show_indices: bool,
+    // クロージャに作った Abs の位置を添える
+    pub show_origin: bool,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 4,
+            max_len: 3,
+            show_indices: true,
+            show_origin: true,
+        }
+    }
+}
+
+impl PrettyOptions {
+    // 一段深いリストを表示する時のもの
+    fn nested(self) -> Option<Self> {
+        let max_depth = self.max_depth.checked_sub(1)?;
+        Some(Self { max_depth, ..self })
+    }
+}
+
+impl<'a, T> PP<'a, T> {
+    pub fn new(value: &'a T) -> Self {
+        PP(value, PrettyOptions::default())
+    }
+}
 
 impl<'a> Debug for PP<'a, State> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("State")
-            .field("code", &PP(&self.0.code))
-            .field("env", &PP(&self.0.env))
-            .field("dump", &PP(&self.0.dump))
+            .field("code", &PP(&self.0.code, self.1))
+            .field("env", &PP(&self.0.env, self.1))
+            .field("dump", &PP(&self.0.dump, self.1))
             .finish()
     }
 }
 
 impl<'a> Debug for PP<'a, Code> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut list = f.debug_list();
-        let mut iter = self.0.iter();
-        // 高々3要素まで表示し、残りがあれば省略表示する
-        for _ in 0..3 {
-            if let Some(instr) = iter.next() {
-                list.entry(&PP(instr));
-            } else {
-                break;
-            }
-        }
-        if iter.next().is_some() {
-            list.entry(&Ellipsis);
-        }
-        list.finish()
+        truncated(f, self.1, self.0.iter().map(|instr| PP(instr, self.1)))
     }
 }
 
 impl<'a> Debug for PP<'a, Instr> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.0 {
-            Instr::App { func_idx, arg_idx } => f
+            Instr::App {
+                func_idx, arg_idx, ..
+            } => f
                 .debug_tuple("_App_")
                 .field(func_idx)
                 .field(arg_idx)
                 .finish(),
-            Instr::Abs { arity, body } => f
-                .debug_tuple("_Abs_")
-                .field(arity)
-                .field(&PP(body))
-                .finish(),
+            Instr::Abs { arity, body, .. } => {
+                let mut tuple = f.debug_tuple("_Abs_");
+                tuple.field(arity);
+                match self.1.nested() {
+                    Some(options) => tuple.field(&PP(body.as_ref(), options)),
+                    None => tuple.field(&Ellipsis),
+                };
+                tuple.finish()
+            }
         }
     }
 }
 
 impl<'a> Debug for PP<'a, Rc<Env>> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut values = Vec::new();
         let mut env = self.0.as_ref();
-        let mut list = f.debug_list();
-        let mut index = 1;
+        // 省略表示の要否が分かるよう、1つ余分に取り出す
         while let Env::Node(v, next) = env {
-            // 高々3要素まで表示し、残りがあれば省略表示する
-            if index > 3 {
-                list.entry(&Ellipsis);
+            if values.len() > self.1.max_len {
                 break;
             }
-            list.entry(&PP(&(index, v)));
+            values.push(v);
             env = next.as_ref();
-            index += 1;
         }
-        list.finish()
+        let entries = values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| EnvEntry(i + 1, v, self.1));
+        truncated(f, self.1, entries)
     }
 }
 
 impl<'a> Debug for PP<'a, Vec<Frame>> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_list()
-            .entries(self.0.iter().map(|frame| PP(frame)))
-            .finish()
+        match self.1.nested() {
+            Some(options) => truncated(f, self.1, self.0.iter().map(|frame| PP(frame, options))),
+            None => f.debug_list().entry(&Ellipsis).finish(),
+        }
     }
 }
 
 impl<'a> Debug for PP<'a, Frame> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Frame")
-            .field("code", &PP(&self.0.code))
-            .field("env", &PP(&self.0.env))
+            .field("code", &PP(&self.0.code, self.1))
+            .field("env", &PP(&self.0.env, self.1))
             .finish()
     }
 }
 
+// 環境の要素。show_indices なら de Bruijn インデックスを添える
 impl<'a> Debug for PP<'a, (usize, &Value)> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Debug::fmt(&self.0.0, f)?;
-        f.write_str(": ")?;
-        Debug::fmt(&PP(self.0.1), f)
+        if self.1.show_indices {
+            Debug::fmt(&self.0.0, f)?;
+            f.write_str(": ")?;
+        }
+        Debug::fmt(&PP(self.0.1, self.1), f)
     }
 }
 
 impl<'a> Debug for PP<'a, Value> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.0 {
-            Value::Char(c) => Debug::fmt(&(*c as char), f),
-            Value::Closure { code, env } => f
-                .debug_struct("Closure")
-                .field("code", &PP(code))
-                .field("env", &PP(env))
+            Value::Char(c) => match char::from_u32(*c) {
+                Some(c) => Debug::fmt(&c, f),
+                None => write!(f, "U+{:04X}", c),
+            },
+            Value::Closure { code, env, origin } => {
+                let (arity, body) = uncurry(code, *origin);
+                f.write_str("<fn")?;
+                if let (true, Some(origin)) = (self.1.show_origin, origin) {
+                    write!(f, "@{}", origin)?;
+                }
+                write!(f, " arity={}", arity)?;
+                if arity == 2 {
+                    match church(body, env) {
+                        Some(Church::FalseOrZero) => f.write_str(" false/0")?,
+                        Some(Church::True) => f.write_str(" true")?,
+                        Some(Church::Numeral(n)) => write!(f, " {}", n)?,
+                        None => {}
+                    }
+                }
+                f.write_str(">")
+            }
+            Value::Prim(prim) => Debug::fmt(&PP(prim, self.1), f),
+            Value::Native(native) => Debug::fmt(native, f),
+            Value::Thunk(thunk) => match thunk.value() {
+                Some(value) => Debug::fmt(&PP(&value, self.1), f),
+                None => Debug::fmt(thunk, f),
+            },
+            Value::Continuation(state) => f
+                .debug_struct("Continuation")
+                .field("code", &PP(&state.code, self.1))
+                .field("frames", &state.dump.len())
                 .finish(),
-            Value::Prim(prim) => Debug::fmt(&PP(prim), f),
         }
     }
 }
@@ -114,6 +176,16 @@ impl<'a> Debug for PP<'a, Prim> {
             Prim::In => write!(f, "In"),
             Prim::Succ => write!(f, "Succ"),
             Prim::Out => write!(f, "Out"),
+            Prim::Exit => write!(f, "Exit"),
+            Prim::Pred => write!(f, "Pred"),
+            Prim::Fail => write!(f, "Fail"),
+            Prim::Eof => write!(f, "Eof"),
+            Prim::Spawn => write!(f, "Spawn"),
+            Prim::Send => write!(f, "Send"),
+            Prim::Recv => write!(f, "Recv"),
+            Prim::Channel(channel) => write!(f, "Channel#{}", channel),
+            Prim::Sender(channel) => write!(f, "Send(Channel#{})", channel),
+            Prim::CallCC => write!(f, "CallCC"),
         }
     }
 }
@@ -125,3 +197,120 @@ impl Debug for Ellipsis {
         write!(f, "...")
     }
 }
+
+struct EnvEntry<'a>(usize, &'a Value, PrettyOptions);
+
+impl<'a> Debug for EnvEntry<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&PP(&(self.0, self.1), self.2), f)
+    }
+}
+
+// 構造から読み取れる Church エンコーディング。λx y. y は偽とも 0 とも読める
+enum Church {
+    FalseOrZero,
+    True,
+    Numeral(usize),
+}
+
+// ========================================================================== //
+
+fn truncated<T: Debug>(
+    f: &mut std::fmt::Formatter<'_>,
+    options: PrettyOptions,
+    mut entries: impl Iterator<Item = T>,
+) -> std::fmt::Result {
+    let mut list = f.debug_list();
+    list.entries(entries.by_ref().take(options.max_len));
+    if entries.next().is_some() {
+        list.entry(&Ellipsis);
+    }
+    list.finish()
+}
+
+// 多引数の Abs は、引数を1つ受け取るごとに同じ位置の Abs を持つクロージャになる
+fn uncurry(code: &Code, origin: Option<SourceRange>) -> (usize, &Code) {
+    match code.front() {
+        Some(Instr::Abs { arity, body, range }) if code.len() == 1 && *range == origin => {
+            (arity.get() + 1, body.as_ref())
+        }
+        _ => (1, code),
+    }
+}
+
+// 2引数のクロージャの本体を見て、Church の真偽値と自然数を見分ける
+fn church(body: &Code, env: &Rc<Env>) -> Option<Church> {
+    if body.is_empty() {
+        return Some(Church::FalseOrZero);
+    }
+    // λf x. f (f (... (f x))) は k 回目の適用の時点で f が k+1 番目にある
+    let numeral = body.iter().enumerate().all(|(k, instr)| {
+        matches!(instr, Instr::App { func_idx, arg_idx, .. }
+            if func_idx.get() == k + 2 && arg_idx.get() == 1)
+    });
+    if numeral {
+        return Some(Church::Numeral(body.len()));
+    }
+    // λx y. x は本体で x を作れないため、外の恒等関数を x に適用して返す
+    if let [
+        Instr::App {
+            func_idx, arg_idx, ..
+        },
+    ] = body.iter().collect::<Vec<_>>()[..]
+        && arg_idx.get() == 2
+        && let Some(outer) = func_idx.get().checked_sub(2).and_then(NonZeroUsize::new)
+        && let Some(Value::Closure { code, .. }) = env.get(outer)
+        && code.is_empty()
+    {
+        return Some(Church::True);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::church;
+    use crate::parser::parse_prog;
+    use crate::vm::VM;
+    use std::io;
+
+    #[test]
+    fn test_church() {
+        let pp = |value: &Value| format!("{:?}", PP::new(value));
+        assert_eq!(pp(&church::boolean(true)), "<fn arity=2 true>");
+        assert_eq!(pp(&church::boolean(false)), "<fn arity=2 false/0>");
+        assert_eq!(pp(&church::numeral(3)), "<fn arity=2 3>");
+        assert_eq!(
+            pp(&church::pair(Value::Char(0x77), Value::Char(0x77))),
+            "<fn arity=1>"
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        // λx y. y y
+        let prog = parse_prog("wwWw").unwrap();
+        let mut vm = VM::new(&prog);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_step_limit(Some(1));
+        assert!(vm.run().is_err());
+        let state = vm.state();
+
+        let options = PrettyOptions::default();
+        assert_eq!(
+            format!("{:?}", PP(&state.env, options)),
+            "[1: <fn@1:1 arity=2>, 2: Out, 3: Succ, ...]"
+        );
+        let options = PrettyOptions {
+            max_len: 1,
+            show_indices: false,
+            show_origin: false,
+            ..options
+        };
+        assert_eq!(
+            format!("{:?}", PP(&state.env, options)),
+            "[<fn arity=2>, ...]"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::ast::SourceRange;
+use crate::ir::{Env, Instr, Prim, State, Value};
+use crate::vm::BlockedTask;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+
+// Spawn で作ったタスクを round-robin で切り替える。実行中のタスクの状態は VM が持つ
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    current: Task,
+    // 実行を待っているタスク。先頭から順に実行する
+    queue: VecDeque<(Task, State)>,
+    // 各チャネルに届いて、まだ Recv されていない値
+    mailboxes: Vec<VecDeque<Value>>,
+    next_id: usize,
+    // 実行中のタスクが切り替わってから進んだステップ数
+    slice: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Task {
+    // 0 は main
+    id: usize,
+    // Spawn した App の位置
+    site: Option<SourceRange>,
+    // Recv で待っているチャネル
+    waiting: Option<u32>,
+}
+
+impl Scheduler {
+    // 一つのタスクに続けて実行させるステップ数
+    pub(crate) const QUANTUM: u64 = 256;
+
+    // 他にタスクが無ければ切り替えは要らない
+    pub(crate) fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub(crate) fn is_main(&self) -> bool {
+        self.current.id == 0
+    }
+
+    // 双方向のチャネルを作り、f をその片側に適用するタスクを加える。もう片側を返す
+    pub(crate) fn spawn(&mut self, f: Value, site: Option<SourceRange>) -> u32 {
+        let channel = self.mailboxes.len() as u32;
+        self.mailboxes.push(VecDeque::new());
+        self.mailboxes.push(VecDeque::new());
+
+        self.next_id += 1;
+        let task = Task {
+            id: self.next_id,
+            site,
+            waiting: None,
+        };
+        let state = State {
+            code: VecDeque::from(vec![Instr::App {
+                func_idx: NonZeroUsize::new(2).unwrap(),
+                arg_idx: NonZeroUsize::new(1).unwrap(),
+                range: site,
+            }]),
+            env: Env::nil()
+                .push(f)
+                .push(Value::Prim(Prim::Channel(channel ^ 1))),
+            dump: Vec::new(),
+        };
+        self.queue.push_back((task, state));
+        channel
+    }
+
+    // チャネルの向こう側に値を送る。送った値はいくらでも溜めておける
+    pub(crate) fn send(&mut self, channel: u32, value: Value) {
+        self.mailboxes[(channel ^ 1) as usize].push_back(value);
+    }
+
+    // 届いた値を取り出す。まだ届いていなければ、実行中のタスクを待ち状態にする
+    pub(crate) fn recv(&mut self, channel: u32) -> Option<Value> {
+        let value = self.mailboxes.get_mut(channel as usize)?.pop_front();
+        self.current.waiting = match value {
+            Some(_) => None,
+            None => Some(channel),
+        };
+        value
+    }
+
+    pub(crate) fn is_channel(&self, channel: u32) -> bool {
+        (channel as usize) < self.mailboxes.len()
+    }
+
+    // 1 ステップ進んだことを数え、持ち時間を使い切ったかどうかを返す
+    pub(crate) fn tick(&mut self) -> bool {
+        self.slice += 1;
+        self.slice >= Self::QUANTUM
+    }
+
+    // 実行中のタスクを列の後ろに回し、次に動けるタスクに切り替える。
+    // 終わったタスクは done で列に戻さない。動けるタスクが無ければ待っているタスクを返す
+    pub(crate) fn switch(&mut self, state: &mut State, done: bool) -> Result<(), Vec<BlockedTask>> {
+        let runnable = self
+            .queue
+            .iter()
+            .position(|(task, _)| self.is_runnable(task));
+        let Some(index) = runnable else {
+            if !done && self.is_runnable(&self.current) {
+                self.slice = 0;
+                return Ok(());
+            }
+            let mut blocked: Vec<_> = self
+                .queue
+                .iter()
+                .map(|(task, _)| task)
+                .chain((!done).then_some(&self.current))
+                .filter_map(|task| {
+                    task.waiting.map(|channel| BlockedTask {
+                        id: task.id,
+                        site: task.site,
+                        channel,
+                    })
+                })
+                .collect();
+            blocked.sort_by_key(|task| task.id);
+            return Err(blocked);
+        };
+
+        let (task, next) = self.queue.remove(index).unwrap();
+        let prev = std::mem::replace(state, next);
+        let prev_task = std::mem::replace(&mut self.current, task);
+        if !done {
+            self.queue.push_back((prev_task, prev));
+        }
+        self.current.waiting = None;
+        self.slice = 0;
+        Ok(())
+    }
+
+    fn is_runnable(&self, task: &Task) -> bool {
+        match task.waiting {
+            None => true,
+            Some(channel) => !self.mailboxes[channel as usize].is_empty(),
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use crate::ast::SourceRange;
+use crate::ir::{Code, Env, Frame, Instr, Prim, State, Value};
+use crate::vm::{CharMode, Strategy};
+use combine::stream::position::SourcePosition;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"GRASSNAP";
+const VERSION: u32 = 4;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("failed to access snapshot: {0}")]
+    Io(#[from] io::Error),
+    #[error("not a grass snapshot")]
+    BadMagic,
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+    #[error("corrupt snapshot: {0}")]
+    Corrupt(&'static str),
+}
+
+// 再開後も同じ意味で動くよう、状態と一緒に保存する VM の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Settings {
+    pub char_mode: CharMode,
+    pub strategy: Strategy,
+}
+
+// スナップショットの形式 (数値は全てリトルエンディアン):
+//
+//   magic "GRASSNAP", version: u32, steps: u64,
+//   char_mode: u8 (0: Byte, 1: Unicode に続けて modulus: u32), strategy: u8,
+//   envs: u32 個の (value, next: u32),
+//   code, env: u32, dump: u32 個の (code, env: u32, call_site, callee)
+//
+// ソース上の位置は、有無を表す 1 バイトに続けて開始・終了位置の行と列を i32 で書く。
+//
+// Env はノード単位で表に並べ、他からは表のインデックスで参照することで
+// Rc の共有関係を保ったまま書き出す。インデックス 0 は Env::Empty を表し、
+// 表の各ノードは自分より前に並んだノードしか参照しない。
+
+pub(crate) fn encode<W: Write>(
+    w: &mut W,
+    state: &State,
+    steps: u64,
+    settings: Settings,
+) -> io::Result<()> {
+    let mut encoder = Encoder {
+        ids: HashMap::new(),
+        nodes: Vec::new(),
+    };
+    encoder.intern(&state.env);
+    for frame in &state.dump {
+        encoder.intern(&frame.env);
+    }
+
+    w.write_all(MAGIC)?;
+    write_u32(w, VERSION)?;
+    write_u64(w, steps)?;
+    match settings.char_mode {
+        CharMode::Byte => w.write_all(&[0])?,
+        CharMode::Unicode { modulus } => {
+            w.write_all(&[1])?;
+            write_u32(w, modulus)?;
+        }
+    }
+    let strategy: u8 = match settings.strategy {
+        Strategy::CallByValue => 0,
+        Strategy::CallByNeed => 1,
+    };
+    w.write_all(&[strategy])?;
+
+    write_len(w, encoder.nodes.len())?;
+    for node in &encoder.nodes {
+        if let Env::Node(value, next) = node.as_ref() {
+            encoder.write_value(w, value)?;
+            write_u32(w, encoder.id(next))?;
+        }
+    }
+
+    encoder.write_code(w, &state.code)?;
+    write_u32(w, encoder.id(&state.env))?;
+    write_len(w, state.dump.len())?;
+    for frame in &state.dump {
+        if frame.demand.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "thunks being evaluated cannot be saved in a snapshot",
+            ));
+        }
+        encoder.write_code(w, &frame.code)?;
+        write_u32(w, encoder.id(&frame.env))?;
+        write_range(w, frame.call_site)?;
+        write_range(w, frame.callee)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn decode<R: Read>(r: &mut R) -> Result<(State, u64, Settings), SnapshotError> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let steps = read_u64(r)?;
+    let char_mode = match read_u8(r)? {
+        0 => CharMode::Byte,
+        1 => CharMode::Unicode {
+            modulus: read_u32(r)?,
+        },
+        _ => return Err(SnapshotError::Corrupt("unknown char mode")),
+    };
+    let strategy = match read_u8(r)? {
+        0 => Strategy::CallByValue,
+        1 => Strategy::CallByNeed,
+        _ => return Err(SnapshotError::Corrupt("unknown strategy")),
+    };
+    let settings = Settings {
+        char_mode,
+        strategy,
+    };
+
+    let mut decoder = Decoder {
+        envs: vec![Env::nil()],
+    };
+    let count = read_u32(r)?;
+    for _ in 0..count {
+        let value = decoder.read_value(r)?;
+        let next = decoder.env(read_u32(r)?)?;
+        decoder.envs.push(next.push(value));
+    }
+
+    let code = decoder.read_code(r)?;
+    let env = decoder.env(read_u32(r)?)?;
+    let mut dump = Vec::new();
+    for _ in 0..read_u32(r)? {
+        let code = decoder.read_code(r)?;
+        let env = decoder.env(read_u32(r)?)?;
+        let call_site = read_range(r)?;
+        let callee = read_range(r)?;
+        dump.push(Frame {
+            code,
+            env,
+            call_site,
+            callee,
+            demand: None,
+        });
+    }
+
+    Ok((State { code, env, dump }, steps, settings))
+}
+
+// ========================================================================== //
+
+struct Encoder {
+    ids: HashMap<*const Env, u32>,
+    nodes: Vec<Rc<Env>>,
+}
+
+impl Encoder {
+    // env とそこから辿れる全ノードに、参照先が先に来るよう番号を振る。
+    // 環境は非常に長い連結リストになり得るので再帰は使わない
+    fn intern(&mut self, env: &Rc<Env>) {
+        let mut stack = vec![env.clone()];
+        while let Some(env) = stack.last().cloned() {
+            let Env::Node(value, next) = env.as_ref() else {
+                stack.pop();
+                continue;
+            };
+            if self.ids.contains_key(&Rc::as_ptr(&env)) {
+                stack.pop();
+                continue;
+            }
+
+            let mut deps = vec![next.clone()];
+            if let Value::Closure { env: captured, .. } = value {
+                deps.push(captured.clone());
+            }
+            let pending: Vec<_> = deps
+                .into_iter()
+                .filter(|dep| matches!(dep.as_ref(), Env::Node(..)))
+                .filter(|dep| !self.ids.contains_key(&Rc::as_ptr(dep)))
+                .collect();
+
+            if pending.is_empty() {
+                stack.pop();
+                self.nodes.push(env.clone());
+                self.ids.insert(Rc::as_ptr(&env), self.nodes.len() as u32);
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+
+    fn id(&self, env: &Rc<Env>) -> u32 {
+        match env.as_ref() {
+            Env::Empty => 0,
+            Env::Node(..) => self.ids[&Rc::as_ptr(env)],
+        }
+    }
+
+    fn write_value<W: Write>(&self, w: &mut W, value: &Value) -> io::Result<()> {
+        match value {
+            Value::Char(c) => {
+                w.write_all(&[0])?;
+                write_u32(w, *c)
+            }
+            Value::Closure { code, env, origin } => {
+                w.write_all(&[1])?;
+                self.write_code(w, code)?;
+                write_u32(w, self.id(env))?;
+                write_range(w, *origin)
+            }
+            Value::Prim(prim) => {
+                let tag = match prim {
+                    Prim::In => 0,
+                    Prim::Succ => 1,
+                    Prim::Out => 2,
+                    Prim::Exit => 3,
+                    Prim::Pred => 4,
+                    Prim::Fail => 5,
+                    Prim::Eof => 6,
+                    Prim::Spawn => 7,
+                    Prim::Send => 8,
+                    Prim::Recv => 9,
+                    Prim::CallCC => 10,
+                    Prim::Channel(_) | Prim::Sender(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "channels cannot be saved in a snapshot",
+                        ));
+                    }
+                };
+                w.write_all(&[2, tag])
+            }
+            Value::Native(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "native functions cannot be saved in a snapshot",
+            )),
+            Value::Thunk(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "thunks cannot be saved in a snapshot",
+            )),
+            Value::Continuation(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "continuations cannot be saved in a snapshot",
+            )),
+        }
+    }
+
+    fn write_code<W: Write>(&self, w: &mut W, code: &Code) -> io::Result<()> {
+        write_len(w, code.len())?;
+        for instr in code {
+            match instr {
+                Instr::App {
+                    func_idx,
+                    arg_idx,
+                    range,
+                } => {
+                    w.write_all(&[0])?;
+                    write_u64(w, func_idx.get() as u64)?;
+                    write_u64(w, arg_idx.get() as u64)?;
+                    write_range(w, *range)?;
+                }
+                Instr::Abs { arity, body, range } => {
+                    w.write_all(&[1])?;
+                    write_u64(w, arity.get() as u64)?;
+                    self.write_code(w, body)?;
+                    write_range(w, *range)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Decoder {
+    envs: Vec<Rc<Env>>,
+}
+
+impl Decoder {
+    fn env(&self, id: u32) -> Result<Rc<Env>, SnapshotError> {
+        self.envs
+            .get(id as usize)
+            .cloned()
+            .ok_or(SnapshotError::Corrupt("dangling environment reference"))
+    }
+
+    fn read_value<R: Read>(&self, r: &mut R) -> Result<Value, SnapshotError> {
+        match read_u8(r)? {
+            0 => Ok(Value::Char(read_u32(r)?)),
+            1 => {
+                let code = self.read_code(r)?;
+                let env = self.env(read_u32(r)?)?;
+                let origin = read_range(r)?;
+                Ok(Value::Closure { code, env, origin })
+            }
+            2 => match read_u8(r)? {
+                0 => Ok(Value::Prim(Prim::In)),
+                1 => Ok(Value::Prim(Prim::Succ)),
+                2 => Ok(Value::Prim(Prim::Out)),
+                3 => Ok(Value::Prim(Prim::Exit)),
+                4 => Ok(Value::Prim(Prim::Pred)),
+                5 => Ok(Value::Prim(Prim::Fail)),
+                6 => Ok(Value::Prim(Prim::Eof)),
+                7 => Ok(Value::Prim(Prim::Spawn)),
+                8 => Ok(Value::Prim(Prim::Send)),
+                9 => Ok(Value::Prim(Prim::Recv)),
+                10 => Ok(Value::Prim(Prim::CallCC)),
+                _ => Err(SnapshotError::Corrupt("unknown primitive")),
+            },
+            _ => Err(SnapshotError::Corrupt("unknown value tag")),
+        }
+    }
+
+    fn read_code<R: Read>(&self, r: &mut R) -> Result<Code, SnapshotError> {
+        let len = read_u32(r)?;
+        let mut code = Code::new();
+        for _ in 0..len {
+            let instr = match read_u8(r)? {
+                0 => Instr::App {
+                    func_idx: read_index(r)?,
+                    arg_idx: read_index(r)?,
+                    range: read_range(r)?,
+                },
+                1 => Instr::Abs {
+                    arity: read_index(r)?,
+                    body: Rc::new(self.read_code(r)?),
+                    range: read_range(r)?,
+                },
+                _ => return Err(SnapshotError::Corrupt("unknown instruction tag")),
+            };
+            code.push_back(instr);
+        }
+        Ok(code)
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, n: u32) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| io::Error::other("state too large"))?;
+    write_u32(w, len)
+}
+
+fn write_range<W: Write>(w: &mut W, range: Option<SourceRange>) -> io::Result<()> {
+    let Some(range) = range else {
+        return w.write_all(&[0]);
+    };
+    w.write_all(&[1])?;
+    for n in [
+        range.start.line,
+        range.start.column,
+        range.end.line,
+        range.end.column,
+    ] {
+        w.write_all(&n.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, SnapshotError> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, SnapshotError> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, SnapshotError> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_i32<R: Read>(r: &mut R) -> Result<i32, SnapshotError> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_range<R: Read>(r: &mut R) -> Result<Option<SourceRange>, SnapshotError> {
+    match read_u8(r)? {
+        0 => Ok(None),
+        1 => {
+            let start = SourcePosition {
+                line: read_i32(r)?,
+                column: read_i32(r)?,
+            };
+            let end = SourcePosition {
+                line: read_i32(r)?,
+                column: read_i32(r)?,
+            };
+            Ok(Some(SourceRange { start, end }))
+        }
+        _ => Err(SnapshotError::Corrupt("invalid source range")),
+    }
+}
+
+fn read_index<R: Read>(r: &mut R) -> Result<NonZeroUsize, SnapshotError> {
+    usize::try_from(read_u64(r)?)
+        .ok()
+        .and_then(NonZeroUsize::new)
+        .ok_or(SnapshotError::Corrupt("invalid index"))
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_env() {
+        let shared = Env::nil().push(Value::Char(b'w'.into()));
+        let closure = Value::Closure {
+            code: Code::new(),
+            env: shared.clone(),
+            origin: None,
+        };
+        let state = State {
+            code: Code::new(),
+            env: shared.push(closure.clone()).push(closure),
+            dump: vec![Frame {
+                code: Code::new(),
+                env: shared,
+                call_site: None,
+                callee: None,
+                demand: None,
+            }],
+        };
+
+        let settings = Settings {
+            char_mode: CharMode::Unicode { modulus: 128 },
+            strategy: Strategy::CallByNeed,
+        };
+        let mut buf = Vec::new();
+        encode(&mut buf, &state, 42, settings).unwrap();
+        let (restored, steps, restored_settings) = decode(&mut buf.as_slice()).unwrap();
+        assert_eq!(steps, 42);
+        assert_eq!(restored_settings, settings);
+
+        let Env::Node(Value::Closure { env: e1, .. }, next) = restored.env.as_ref() else {
+            panic!("unexpected env: {:?}", restored.env);
+        };
+        let Env::Node(Value::Closure { env: e2, .. }, base) = next.as_ref() else {
+            panic!("unexpected env: {:?}", next);
+        };
+        assert!(Rc::ptr_eq(e1, e2));
+        assert!(Rc::ptr_eq(e1, base));
+        assert!(Rc::ptr_eq(e1, &restored.dump[0].env));
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let result = decode(&mut b"GRASSNAX\x01\x00\x00\x00".as_slice());
+        assert!(matches!(result, Err(SnapshotError::BadMagic)));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::ast::SourceRange;
+use crate::ir::State;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace {
+    // 内側の呼び出しから順に並ぶ
+    pub frames: Vec<StackFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    // 実行中だった関数を作った Abs の位置。None はトップレベルの main
+    pub function: Option<SourceRange>,
+    // その関数の中で実行中だった App の位置
+    pub site: Option<SourceRange>,
+    // 同じ呼び出しが連続した回数
+    pub repeat: usize,
+}
+
+impl StackTrace {
+    // site は実行中だった App の位置
+    pub fn capture(state: &State, site: Option<SourceRange>) -> Self {
+        let mut trace = StackTrace { frames: Vec::new() };
+        let mut site = site;
+        for frame in state.dump.iter().rev() {
+            // サンクの評価のために積んだ Frame は関数呼び出しではない
+            if frame.demand.is_some() {
+                continue;
+            }
+            trace.push(frame.callee, site);
+            site = frame.call_site;
+        }
+        // main の本体から呼ばれた関数の Frame しか残っていない場合
+        if site.is_some() {
+            trace.push(None, site);
+        }
+        trace
+    }
+
+    fn push(&mut self, function: Option<SourceRange>, site: Option<SourceRange>) {
+        if let Some(last) = self.frames.last_mut()
+            && last.function == function
+            && last.site == site
+        {
+            last.repeat += 1;
+            return;
+        }
+        self.frames.push(StackFrame {
+            function,
+            site,
+            repeat: 1,
+        });
+    }
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:>4}: {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.function {
+            Some(range) => write!(f, "fn@{}", range)?,
+            None => write!(f, "<main>")?,
+        }
+        if let Some(site) = self.site {
+            write!(f, " at {}", site)?;
+        }
+        if self.repeat > 1 {
+            write!(f, " (repeated {} times)", self.repeat)?;
+        }
+        Ok(())
+    }
+}
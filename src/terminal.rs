@@ -0,0 +1,100 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+
+// raw モードにする前の端末の設定。終了する時に元に戻す
+static SAVED: Mutex<Option<(RawFd, libc::termios)>> = Mutex::new(None);
+
+// 端末を非カノニカル・エコー無しにして、キーを押すたびに読めるようにする。
+// Ctrl-C のシグナルと出力の改行の変換はそのまま残す
+pub fn enable_raw_mode(fd: RawFd) -> io::Result<()> {
+    let original = get_attr(fd)?;
+    let mut raw = original;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    set_attr(fd, &raw)?;
+
+    let mut saved = SAVED.lock().unwrap_or_else(|err| err.into_inner());
+    if saved.is_none() {
+        *saved = Some((fd, original));
+    }
+    Ok(())
+}
+
+// enable_raw_mode() の前の設定に戻す。何度呼んでもよい
+pub fn restore() -> io::Result<()> {
+    let saved = SAVED.lock().unwrap_or_else(|err| err.into_inner()).take();
+    match saved {
+        Some((fd, original)) => set_attr(fd, &original),
+        None => Ok(()),
+    }
+}
+
+// ========================================================================== //
+
+fn get_attr(fd: RawFd) -> io::Result<libc::termios> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: tcgetattr は成功した時に termios を全て書き込む
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { termios.assume_init() })
+}
+
+fn set_attr(fd: RawFd, termios: &libc::termios) -> io::Result<()> {
+    // SAFETY: termios は tcgetattr で得た値を元にしている
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    // 擬似端末を開き、制御側と端末側の組を返す
+    fn open_pty() -> (File, File) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(master >= 0, "posix_openpt: {}", io::Error::last_os_error());
+            assert_eq!(libc::grantpt(master), 0);
+            assert_eq!(libc::unlockpt(master), 0);
+            let name = CStr::from_ptr(libc::ptsname(master)).to_owned();
+            let slave = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+            assert!(slave >= 0, "open: {}", io::Error::last_os_error());
+            (File::from_raw_fd(master), File::from_raw_fd(slave))
+        }
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        let (mut master, mut slave) = open_pty();
+        let fd = slave.as_raw_fd();
+        let original = get_attr(fd).unwrap();
+        assert_ne!(original.c_lflag & libc::ICANON, 0);
+
+        enable_raw_mode(fd).unwrap();
+        let raw = get_attr(fd).unwrap();
+        assert_eq!(raw.c_lflag & (libc::ICANON | libc::ECHO), 0);
+        assert_ne!(raw.c_lflag & libc::ISIG, 0);
+
+        // 改行を待たずに 1 バイトずつ読める
+        master.write_all(b"ab").unwrap();
+        let mut buf = [0u8; 1];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+
+        restore().unwrap();
+        let restored = get_attr(fd).unwrap();
+        assert_eq!(restored.c_lflag, original.c_lflag);
+        restore().unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+use crate::ast::SourceRange;
+use crate::flight_recorder::{Rule, Transition};
+use crate::io::IoEvent;
+use crate::ir::{Prim, Value};
+use crate::observer::Observer;
+use crate::pp::{PP, PrettyOptions};
+use serde_json::{Value as Json, json};
+use std::io::Write;
+use std::num::NonZeroUsize;
+
+// 遷移ごとに一つの JSON オブジェクトを一行で書き出す
+pub struct JsonTrace {
+    w: Box<dyn Write>,
+    level: TraceLevel,
+    // 実行中の遷移で適用した関数と引数、得られた値
+    func: Option<String>,
+    arg: Option<String>,
+    result: Option<String>,
+    // 書き出しに失敗したら以降は何もしない
+    failed: bool,
+    pretty: PrettyOptions,
+}
+
+// 書き出す遷移
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceLevel {
+    // 入出力を行った遷移だけ
+    Io,
+    // App, Return, SelfApply。Abs を除く
+    Calls,
+    #[default]
+    All,
+}
+
+impl JsonTrace {
+    pub fn new(w: Box<dyn Write>, level: TraceLevel) -> Self {
+        Self {
+            w,
+            level,
+            func: None,
+            arg: None,
+            result: None,
+            failed: false,
+            pretty: PrettyOptions::default(),
+        }
+    }
+
+    // func, arg, result に値を書く時の設定
+    pub fn set_pretty_options(&mut self, options: PrettyOptions) {
+        self.pretty = options;
+    }
+
+    fn is_traced(&self, transition: &Transition) -> bool {
+        match self.level {
+            TraceLevel::Io => transition.io.is_some(),
+            TraceLevel::Calls => !matches!(transition.rule, Rule::Abs { .. }),
+            TraceLevel::All => true,
+        }
+    }
+}
+
+impl Observer for JsonTrace {
+    fn on_app(&mut self, func: &Value, arg: &Value, _site: Option<SourceRange>) {
+        self.func = Some(summary(func, self.pretty));
+        self.arg = Some(summary(arg, self.pretty));
+    }
+
+    fn on_abs(&mut self, _arity: NonZeroUsize, closure: &Value) {
+        self.result = Some(summary(closure, self.pretty));
+    }
+
+    fn on_return(&mut self, value: &Value) {
+        self.result = Some(summary(value, self.pretty));
+    }
+
+    fn on_prim(&mut self, _prim: &Prim, _arg: &Value, result: &Value) {
+        self.result = Some(summary(result, self.pretty));
+    }
+
+    fn on_transition(&mut self, transition: &Transition) {
+        let func = self.func.take();
+        let arg = self.arg.take();
+        let result = self.result.take();
+        if self.failed || !self.is_traced(transition) {
+            return;
+        }
+
+        let (rule, instr, range) = match transition.rule {
+            Rule::App {
+                func_idx,
+                arg_idx,
+                range,
+            } => (
+                "App",
+                json!({ "func_idx": func_idx, "arg_idx": arg_idx }),
+                range,
+            ),
+            Rule::Abs { arity, range } => ("Abs", json!({ "arity": arity }), range),
+            Rule::Return => ("Return", Json::Null, None),
+            Rule::SelfApply => ("SelfApply", Json::Null, None),
+        };
+        let io = match transition.io {
+            Some(IoEvent::In(c)) => json!({ "in": c }),
+            Some(IoEvent::Eof) => json!({ "eof": true }),
+            Some(IoEvent::Out(c)) => json!({ "out": c }),
+            None => Json::Null,
+        };
+        let span = match range {
+            Some(range) => json!({
+                "start": [range.start.line, range.start.column],
+                "end": [range.end.line, range.end.column],
+            }),
+            None => Json::Null,
+        };
+        let line = json!({
+            "step": transition.step,
+            "rule": rule,
+            "instr": instr,
+            "span": span,
+            "env_depth": transition.env_depth(),
+            "dump_depth": transition.dump_depth,
+            "io": io,
+            "func": func,
+            "arg": arg,
+            "result": result,
+        });
+        if writeln!(self.w, "{}", line).is_err() {
+            self.failed = true;
+        }
+    }
+}
+
+// ========================================================================== //
+
+fn summary(value: &Value, options: PrettyOptions) -> String {
+    format!("{:?}", PP(value, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::SharedBuffer;
+    use crate::parser::parse_prog;
+    use crate::vm::VM;
+    use std::io;
+
+    fn trace(level: TraceLevel, options: PrettyOptions) -> Vec<Json> {
+        // λx. Out (In x)
+        let prog = parse_prog("wWWWWWwWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        let captured = SharedBuffer::default();
+        vm.set_output(Box::new(io::sink()));
+        let mut trace = JsonTrace::new(Box::new(captured.clone()), level);
+        trace.set_pretty_options(options);
+        vm.add_observer(Box::new(trace));
+        vm.feed(b"a");
+        vm.run().unwrap();
+
+        let bytes = captured.contents();
+        std::str::from_utf8(&bytes)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_levels() {
+        let all = trace(TraceLevel::All, PrettyOptions::default());
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[0]["rule"], "Abs");
+        assert_eq!(all[0]["result"], "<fn@1:1 arity=1>");
+
+        let io = trace(TraceLevel::Io, PrettyOptions::default());
+        assert_eq!(io.len(), 2);
+        assert_eq!(io[0]["io"], json!({ "in": 97 }));
+        assert_eq!(io[0]["instr"], json!({ "func_idx": 5, "arg_idx": 1 }));
+        assert_eq!(io[0]["span"], json!({ "start": [1, 2], "end": [1, 8] }));
+        assert_eq!(
+            (&io[0]["func"], &io[0]["result"]),
+            (&json!("In"), &json!("'a'"))
+        );
+        assert_eq!(io[1]["io"], json!({ "out": 97 }));
+
+        let calls = trace(TraceLevel::Calls, PrettyOptions::default());
+        assert!(calls.iter().all(|line| line["rule"] != "Abs"));
+        assert_eq!(calls.len(), all.len() - 1);
+    }
+
+    #[test]
+    fn test_pretty_options() {
+        let options = PrettyOptions {
+            show_origin: false,
+            ..PrettyOptions::default()
+        };
+        let all = trace(TraceLevel::All, options);
+        assert_eq!(all[0]["result"], "<fn arity=1>");
+    }
+}
@@ -1,210 +1,1690 @@
-use crate::ast::Prog;
+use crate::ast::{Prog, SourceRange};
+use crate::church;
+use crate::fault::FaultPlan;
+use crate::flight_recorder::{FlightRecorder, Transition};
+use crate::io::{Fetch, Input, IoEvent, Replay, Source};
 use crate::ir::{self, Prim, Value};
+use crate::loop_detector::LoopDetector;
+use crate::observer::Observer;
 use crate::pp::PP;
+use crate::scheduler::Scheduler;
+use crate::snapshot::{self, Settings, SnapshotError};
+use crate::stack_trace::StackTrace;
 use std::collections::VecDeque;
-use std::io::Read;
+use std::io::Write;
 use std::num::NonZeroUsize;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
 use tracing::debug;
 
 pub struct VM {
     state: ir::State,
+    input: Input,
+    steps: u64,
+    // トップレベルの定義の数
+    items: usize,
+    step_limit: Option<u64>,
+    interrupt: InterruptHandle,
+    recorder: FlightRecorder,
+    // 実行中の遷移で起きた入出力
+    io_event: Option<IoEvent>,
+    observers: Vec<Box<dyn Observer>>,
+    char_mode: CharMode,
+    // Exit で終了した時の終了ステータス
+    exit_code: Option<u32>,
+    strategy: Strategy,
+    loop_detector: Option<LoopDetector>,
+    scheduler: Scheduler,
+    faults: FaultPlan,
+    // Out の書き出し先。None なら標準出力
+    output: Option<Box<dyn Write>>,
+    // reset() で戻る状態
+    initial: ir::State,
+    initial_steps: u64,
+}
+
+// AST を IR に変換したもの。同じプログラムから何度でも VM を作れる
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    // Abs の本体は Rc なので、VM ごとに複製するのはトップレベルの命令列だけ
+    code: Rc<ir::Code>,
+    // トップレベルの定義の数
+    items: usize,
+}
+
+// 別スレッドから run() を止めるためのハンドル
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+// 文字の幅と入出力の符号化
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CharMode {
+    // 文字は 1 バイトで、Succ は 256 で一周する。仕様通りの動作
+    #[default]
+    Byte,
+    // 文字は Unicode の符号位置で、入出力は UTF-8 で符号化する
+    Unicode {
+        modulus: u32,
+    },
+}
+
+// 初期環境に置くプリミティブの組
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dialect {
+    // 仕様通りの Out, Succ, w, In だけ
+    #[default]
+    Standard,
+    // In より奥に Exit, Pred, Fail, Eof を加える
+    Extended,
+    // In より奥に Spawn, Send, Recv を加える
+    Concurrent,
+    // In より奥に CallCC を加える
+    Continuation,
+}
+
+// 評価戦略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    // App をその場で評価する。仕様通りの動作
+    #[default]
+    CallByValue,
+    // App をサンクとして積み、値が必要になった時に一度だけ評価する
+    CallByNeed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Halted,
+    Suspended(Suspended),
+    // Exit が適用された
+    Exited(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suspended {
+    // バッファに入力が無い状態で In が適用された
+    NeedInput,
+    // run_for() で指定したステップ数を使い切った
+    OutOfSteps,
+    // Recv が空のチャネルを待っている。run() は他のタスクに切り替えるので、これを返さない
+    Blocked,
+}
+
+// Recv で待ったまま動けなくなったタスク
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockedTask {
+    // 0 は main
+    pub id: usize,
+    // Spawn した App の位置
+    pub site: Option<SourceRange>,
+    pub channel: u32,
 }
 
 #[derive(Debug, Error)]
 pub enum RuntimeError {
-    #[error("out of bounds access at index {0}")]
-    IndexOutOfBounds(NonZeroUsize),
+    #[error("out of bounds access at index {index}")]
+    IndexOutOfBounds {
+        index: NonZeroUsize,
+        trace: StackTrace,
+    },
     #[error("illegal state encountered")]
     IllegalState,
-    #[error("expected a character value, found {0:?}")]
-    NotAChar(Value),
+    #[error("interrupted")]
+    Interrupted,
+    #[error("expected a character value, found {value:?}")]
+    NotAChar { value: Value, trace: StackTrace },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("program requested more input than was recorded")]
+    ReplayExhausted,
+    #[error("replay diverged: EOF was recorded at step {expected}, but requested at step {actual}")]
+    ReplayDiverged { expected: u64, actual: u64 },
+    #[error("step limit of {0} exceeded")]
+    StepLimitExceeded(u64),
+    #[error("evaluation suspended: {0:?}")]
+    Suspended(Suspended),
+    #[error("program exited with status {0}")]
+    Exited(u32),
+    #[error("program failed: {message}")]
+    Failed { message: String, trace: StackTrace },
+    #[error("expected a character or a list of characters, found {value:?}")]
+    NotAMessage { value: Value, trace: StackTrace },
+    #[error("thunk depends on its own value")]
+    ThunkCycle,
+    #[error("expected a channel, found {value:?}")]
+    NotAChannel { value: Value, trace: StackTrace },
+    #[error("deadlock: every task is waiting on Recv{}", waiting_tasks(.tasks))]
+    Deadlock { tasks: Vec<BlockedTask> },
+    #[error("infinite loop without I/O since step {since}{}", in_functions(.functions))]
+    InfiniteLoop {
+        since: u64,
+        functions: Vec<SourceRange>,
+    },
 }
 
 impl VM {
     pub fn new(prog: &Prog) -> Self {
-        let code0 = ir::Code::from(prog);
+        CompiledProgram::new(prog).vm()
+    }
 
-        let env0 = ir::Env::nil()
-            .push(Value::Prim(Prim::In))
-            .push(Value::Char(b'w'))
-            .push(Value::Prim(Prim::Succ))
-            .push(Value::Prim(Prim::Out));
+    // 入力が尽きると run() が Suspended::NeedInput を返して中断する VM を作る
+    pub fn resumable(prog: &Prog) -> Self {
+        CompiledProgram::new(prog).resumable()
+    }
 
-        let dump0 = vec![ir::Frame {
-            code: VecDeque::new(),
-            env: ir::Env::nil(),
-        }];
+    // save_snapshot() で書き出した状態から、保存時と同じ設定で実行を再開する VM を作る
+    pub fn load_snapshot<R: std::io::Read>(r: &mut R) -> Result<Self, SnapshotError> {
+        let (state, steps, settings) = snapshot::decode(r)?;
+        debug!("load: {:?}", PP::new(&state));
+        let mut vm = Self::from_state(state, Input::new(Source::Stdin), steps);
+        vm.char_mode = settings.char_mode;
+        vm.strategy = settings.strategy;
+        Ok(vm)
+    }
 
-        let state = ir::State {
-            code: code0,
-            env: env0,
-            dump: dump0,
+    fn from_state(state: ir::State, input: Input, steps: u64) -> Self {
+        Self {
+            initial: state.clone(),
+            initial_steps: steps,
+            state,
+            input,
+            steps,
+            items: 0,
+            step_limit: None,
+            interrupt: InterruptHandle::default(),
+            recorder: FlightRecorder::default(),
+            io_event: None,
+            observers: Vec::new(),
+            char_mode: CharMode::Byte,
+            exit_code: None,
+            strategy: Strategy::CallByValue,
+            loop_detector: None,
+            scheduler: Scheduler::default(),
+            faults: FaultPlan::default(),
+            output: None,
+        }
+    }
+
+    // バッファ済みの入力はスナップショットに含まれない
+    pub fn save_snapshot<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        if !self.scheduler.is_idle() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "tasks cannot be saved in a snapshot",
+            ));
+        }
+        let settings = Settings {
+            char_mode: self.char_mode,
+            strategy: self.strategy,
         };
+        snapshot::encode(w, &self.state, self.steps, settings)
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn state(&self) -> &ir::State {
+        &self.state
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    // 別の VM と同じハンドルで止まるようにする
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    // 通算のステップ数が limit に達すると RuntimeError::StepLimitExceeded で止まる
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    pub fn flight_recorder(&self) -> &FlightRecorder {
+        &self.recorder
+    }
+
+    // 直近 capacity 個の遷移を覚えておく。0 で記録をやめる
+    pub fn set_flight_recorder(&mut self, capacity: usize) {
+        self.recorder = FlightRecorder::new(capacity);
+    }
+
+    pub fn char_mode(&self) -> CharMode {
+        self.char_mode
+    }
 
-        debug!("init: {:?}", PP(&state));
+    pub fn set_char_mode(&mut self, mode: CharMode) {
+        self.char_mode = mode;
+    }
 
-        Self { state }
+    // 初期環境のプリミティブを差し替える。実行を始める前にだけ効果がある
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        if self.steps == 0 {
+            self.state.env = initial_env(dialect);
+            self.initial.env = self.state.env.clone();
+        }
     }
 
-    pub fn run(&mut self) -> Result<(), RuntimeError> {
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    // 入出力を挟まずに同じ構成に戻ってきたら RuntimeError::InfiniteLoop で止まる
+    pub fn set_loop_detection(&mut self, enabled: bool) {
+        self.loop_detector = enabled.then(|| LoopDetector::new(LoopDetector::DEFAULT_CAPACITY));
+    }
+
+    // In と Out に故障を注入する。回数は set_fault_plan() した時から数える
+    pub fn set_fault_plan(&mut self, plan: FaultPlan) {
+        self.faults = plan;
+    }
+
+    // Out が書き出すバイト列を標準出力の代わりに w へ送る
+    pub fn set_output(&mut self, w: Box<dyn Write>) {
+        self.output = Some(w);
+    }
+
+    // 作った直後の状態に戻す。設定はそのまま残し、バッファ済みの入力は捨てる。
+    // 再生中のセッションは先頭から読み直す
+    pub fn reset(&mut self) {
+        self.state = self.initial.clone();
+        self.steps = self.initial_steps;
+        self.exit_code = None;
+        self.io_event = None;
+        self.scheduler = Scheduler::default();
+        self.faults.rewind();
+        self.recorder.clear();
+        if let Some(detector) = self.loop_detector.as_mut() {
+            detector.reset();
+        }
+        self.input.reset();
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.feed(bytes);
+    }
+
+    pub fn feed_eof(&mut self) {
+        self.input.feed_eof();
+    }
+
+    // In が返した全てのバイトと EOF のステップ番号を w に書き出す
+    pub fn record_input(&mut self, w: Box<dyn std::io::Write>) {
+        self.input.record(w);
+    }
+
+    // 以降の In には実際の入力の代わりに記録済みのセッションを返す
+    pub fn replay_input(&mut self, replay: Replay) {
+        self.input.replay(replay);
+    }
+
+    pub fn run(&mut self) -> Result<Status, RuntimeError> {
         loop {
-            debug!("loop: {:?}", PP(&self.state));
-
-            match self.state.code.pop_front() {
-                Some(instr) => match instr {
-                    ir::Instr::App { func_idx, arg_idx } => {
-                        let ff = self
-                            .state
-                            .env
-                            .get(func_idx)
-                            .ok_or_else(|| RuntimeError::IndexOutOfBounds(func_idx))
-                            .cloned()?;
-                        let fa = self
-                            .state
-                            .env
-                            .get(arg_idx)
-                            .ok_or_else(|| RuntimeError::IndexOutOfBounds(arg_idx))
-                            .cloned()?;
-                        self.call(ff, fa)?;
-                    }
-                    ir::Instr::Abs { arity, body } => {
-                        if arity.get() == 1 {
-                            self.state.env = self.state.env.push(Value::Closure {
-                                code: body,
-                                env: self.state.env.clone(),
-                            });
-                        } else {
-                            let decrement = ir::Instr::Abs {
-                                arity: NonZeroUsize::new(arity.get() - 1).unwrap(),
-                                body,
-                            };
-                            self.state.env = self.state.env.push(Value::Closure {
-                                code: VecDeque::from(vec![decrement]),
-                                env: self.state.env.clone(),
-                            });
+            if let Some(status) = self.tick()? {
+                return Ok(status);
+            }
+        }
+    }
+
+    pub fn run_for(&mut self, steps: u64) -> Result<Status, RuntimeError> {
+        for _ in 0..steps {
+            if let Some(status) = self.tick()? {
+                return Ok(status);
+            }
+        }
+        Ok(Status::Suspended(Suspended::OutOfSteps))
+    }
+
+    // 1 ステップ進め、必要なら別のタスクに切り替える。main が終われば他のタスクも終わる
+    fn tick(&mut self) -> Result<Option<Status>, RuntimeError> {
+        let status = self.step()?;
+        let done = match status {
+            Some(Status::Suspended(Suspended::Blocked)) => false,
+            None if self.scheduler.is_idle() => return Ok(None),
+            None if !self.scheduler.is_main() && self.is_finished() => true,
+            None if self.scheduler.tick() => false,
+            _ => return Ok(status),
+        };
+        self.scheduler
+            .switch(&mut self.state, done)
+            .map_err(|tasks| RuntimeError::Deadlock { tasks })?;
+        // 切り替えた先のタスクは以前と同じ構成から再開するので、ループとみなさない
+        if let Some(detector) = self.loop_detector.as_mut() {
+            detector.reset();
+        }
+        Ok(None)
+    }
+
+    // Spawn したタスクが関数の値を返し終えた
+    fn is_finished(&self) -> bool {
+        self.state.code.is_empty()
+            && self.state.dump.is_empty()
+            && matches!(
+                self.state
+                    .env
+                    .get(NonZeroUsize::new(1).unwrap())
+                    .cloned()
+                    .map(resolve),
+                Some(Ok(_))
+            )
+    }
+
+    // トップレベルの定義だけを評価し、定義順に並べて返す。main の自己適用は行わない
+    pub fn definitions(&mut self) -> Result<Vec<Value>, RuntimeError> {
+        while !(self.state.code.is_empty() && self.state.dump.len() <= 1) {
+            self.step_to_value()?;
+        }
+
+        let mut values = Vec::with_capacity(self.items);
+        let mut env = self.state.env.as_ref();
+        for _ in 0..self.items {
+            let ir::Env::Node(value, next) = env else {
+                return Err(RuntimeError::IllegalState);
+            };
+            values.push(value.clone());
+            env = next.as_ref();
+        }
+        values.reverse();
+        Ok(values)
+    }
+
+    // func を arg に適用した結果を返す。実行中の状態には影響しない
+    pub fn apply(&mut self, func: Value, arg: Value) -> Result<Value, RuntimeError> {
+        let call = ir::State {
+            code: VecDeque::from(vec![ir::Instr::App {
+                func_idx: NonZeroUsize::new(2).unwrap(),
+                arg_idx: NonZeroUsize::new(1).unwrap(),
+                range: None,
+            }]),
+            env: ir::Env::nil().push(func).push(arg),
+            dump: Vec::new(),
+        };
+        let saved = std::mem::replace(&mut self.state, call);
+        let result = self.eval();
+        // エラーや中断で評価し終えなかったサンクは、次に必要になった時に評価し直す
+        let call = std::mem::replace(&mut self.state, saved);
+        for frame in &call.dump {
+            if let Some(ir::Demand::Update(thunk)) = &frame.demand {
+                thunk.abandon();
+            }
+        }
+        result
+    }
+
+    fn eval(&mut self) -> Result<Value, RuntimeError> {
+        loop {
+            if self.state.code.is_empty() && self.state.dump.is_empty() {
+                let value = self
+                    .state
+                    .env
+                    .get(NonZeroUsize::new(1).unwrap())
+                    .cloned()
+                    .ok_or(RuntimeError::IllegalState)?;
+                // 未評価のサンクは次のステップで評価される
+                if let Ok(value) = resolve(value) {
+                    return Ok(value);
+                }
+            }
+            self.step_to_value()?;
+        }
+    }
+
+    // 値を得るまで進める途中で止まってしまった場合はエラーにする
+    fn step_to_value(&mut self) -> Result<(), RuntimeError> {
+        match self.step()? {
+            None => Ok(()),
+            Some(Status::Suspended(suspended)) => Err(RuntimeError::Suspended(suspended)),
+            Some(Status::Halted) => Err(RuntimeError::IllegalState),
+            Some(Status::Exited(code)) => Err(RuntimeError::Exited(code)),
+        }
+    }
+
+    fn step(&mut self) -> Result<Option<Status>, RuntimeError> {
+        if let Some(code) = self.exit_code {
+            return Ok(Some(Status::Exited(code)));
+        }
+        if self.interrupt.take() {
+            return Err(RuntimeError::Interrupted);
+        }
+        if let Some(limit) = self.step_limit
+            && self.steps >= limit
+        {
+            return Err(RuntimeError::StepLimitExceeded(limit));
+        }
+
+        if let Some(detector) = self.loop_detector.as_mut()
+            && let Some((since, functions)) = detector.visit(&self.state, self.steps)
+        {
+            return Err(RuntimeError::InfiniteLoop { since, functions });
+        }
+
+        let transition = (self.recorder.is_enabled() || !self.observers.is_empty())
+            .then(|| Transition::begin(&self.state, self.steps));
+        let result = self.transition();
+        let io = self.io_event.take();
+        if let (Some(detector), Some(_)) = (self.loop_detector.as_mut(), io) {
+            detector.reset();
+        }
+        // 中断した遷移は再開時にやり直すので記録しない
+        if let (Some(mut transition), Ok(None) | Err(_)) = (transition, &result) {
+            transition.io = io;
+            for observer in &mut self.observers {
+                observer.on_transition(&transition);
+            }
+            self.recorder.push(transition);
+        }
+
+        let status = result?;
+        if status.is_none() {
+            self.steps += 1;
+        }
+        Ok(status)
+    }
+
+    fn transition(&mut self) -> Result<Option<Status>, RuntimeError> {
+        debug!("loop: {:?}", PP::new(&self.state));
+
+        match self.state.code.pop_front() {
+            Some(instr) => match instr {
+                ir::Instr::App {
+                    func_idx,
+                    arg_idx,
+                    range,
+                } => {
+                    let ff = self.lookup(func_idx, range)?;
+                    let fa = self.lookup(arg_idx, range)?;
+                    if self.strategy == Strategy::CallByNeed {
+                        let thunk = ir::Thunk::new(ff, fa, range);
+                        self.state.env = self.state.env.push(Value::Thunk(thunk));
+                        return Ok(None);
+                    }
+                    if let Some(suspended) = self.call(ff, fa, range)? {
+                        // 同じ状態から再開できるよう、命令を戻しておく
+                        self.state.code.push_front(ir::Instr::App {
+                            func_idx,
+                            arg_idx,
+                            range,
+                        });
+                        return Ok(Some(Status::Suspended(suspended)));
+                    }
+                }
+                ir::Instr::Abs { arity, body, range } => {
+                    if arity.get() == 1 {
+                        self.state.env = self.state.env.push(Value::Closure {
+                            code: Rc::unwrap_or_clone(body),
+                            env: self.state.env.clone(),
+                            origin: range,
+                        });
+                    } else {
+                        let decrement = ir::Instr::Abs {
+                            arity: NonZeroUsize::new(arity.get() - 1).unwrap(),
+                            body,
+                            range,
+                        };
+                        self.state.env = self.state.env.push(Value::Closure {
+                            code: VecDeque::from(vec![decrement]),
+                            env: self.state.env.clone(),
+                            origin: range,
+                        });
+                    }
+                    if let ir::Env::Node(closure, _) = self.state.env.as_ref() {
+                        for observer in &mut self.observers {
+                            observer.on_abs(arity, closure);
                         }
                     }
-                },
-                None => {
-                    if let Some(frame) = self.state.dump.pop() {
-                        let return_value = self
-                            .state
-                            .env
-                            .get(NonZeroUsize::new(1).unwrap())
-                            .cloned()
-                            .ok_or_else(|| RuntimeError::IllegalState)?;
-                        self.state.code = frame.code;
-                        self.state.env = frame.env.push(return_value);
-                        continue;
-                    }
-
-                    let result_value = self
+                }
+            },
+            None => {
+                if let Some(frame) = self.state.dump.pop() {
+                    let return_value = self
                         .state
                         .env
                         .get(NonZeroUsize::new(1).unwrap())
                         .cloned()
-                        .ok_or_else(|| RuntimeError::IllegalState)?;
-                    let self_value = result_value.clone();
-                    match result_value {
-                        Value::Closure { code, env } => {
-                            self.state.code = code;
-                            self.state.env = env.push(self_value);
-                            continue;
+                        .ok_or(RuntimeError::IllegalState)?;
+                    if frame.demand.is_some() {
+                        return self.satisfy(frame, return_value);
+                    }
+                    for observer in &mut self.observers {
+                        observer.on_return(&return_value);
+                    }
+                    self.state.code = frame.code;
+                    self.state.env = frame.env.push(return_value);
+                    return Ok(None);
+                }
+
+                let result_value = self
+                    .state
+                    .env
+                    .get(NonZeroUsize::new(1).unwrap())
+                    .cloned()
+                    .ok_or(RuntimeError::IllegalState)?;
+                // main の結果がサンクなら、先に評価してから自己適用する
+                let result_value = match resolve(result_value) {
+                    Ok(value) => value,
+                    Err(thunk) => {
+                        self.force(thunk)?;
+                        return Ok(None);
+                    }
+                };
+                let self_value = result_value.clone();
+                match result_value {
+                    Value::Closure {
+                        code, env, origin, ..
+                    } => {
+                        for observer in &mut self.observers {
+                            observer.on_app(&self_value, &self_value, None);
+                        }
+                        if let Some(detector) = self.loop_detector.as_mut() {
+                            detector.enter(self.steps, origin);
                         }
-                        _ => return Ok(()),
+                        self.state.code = code;
+                        self.state.env = env.push(self_value);
                     }
+                    _ => return Ok(Some(Status::Halted)),
                 }
             }
         }
+        Ok(None)
     }
 
-    fn call(&mut self, func: Value, arg: Value) -> Result<(), RuntimeError> {
-        debug!("call: func: {:?}, arg: {:?}", PP(&func), PP(&arg));
+    // サンクを評価し終えた Frame に値を返す
+    fn satisfy(
+        &mut self,
+        mut frame: ir::Frame,
+        value: Value,
+    ) -> Result<Option<Status>, RuntimeError> {
+        let value = match resolve(value) {
+            Ok(value) => value,
+            Err(thunk) => {
+                // 返ってきた値もサンクなら、この Frame は残したまま続けて評価する
+                self.state.dump.push(frame);
+                self.force(thunk)?;
+                return Ok(None);
+            }
+        };
+        let Some(demand) = frame.demand.clone() else {
+            return Err(RuntimeError::IllegalState);
+        };
+        let (func, arg, site) = match demand {
+            ir::Demand::Update(thunk) => {
+                thunk.replace(ir::ThunkState::Done(value.clone()));
+                self.state.code = frame.code;
+                self.state.env = frame.env.push(value);
+                return Ok(None);
+            }
+            ir::Demand::ApplyTo { arg, site } => (value, arg, site),
+            ir::Demand::ArgOf { func, site } => (func, value, site),
+        };
+
+        let env = std::mem::replace(&mut self.state.env, frame.env);
+        self.state.code = frame.code;
+        if let Some(suspended) = self.call(func, arg, site)? {
+            // 同じ状態から再開できるよう、Frame を戻しておく
+            frame.code = std::mem::take(&mut self.state.code);
+            frame.env = std::mem::replace(&mut self.state.env, env);
+            self.state.dump.push(frame);
+            return Ok(Some(Status::Suspended(suspended)));
+        }
+        Ok(None)
+    }
+
+    // サンクの関数と引数を取り出して評価を始める。結果は Demand::Update でサンクに書き戻す
+    fn force(&mut self, thunk: ir::Thunk) -> Result<(), RuntimeError> {
+        let (func, arg, site) = match thunk.state() {
+            ir::ThunkState::Delayed { func, arg, site } => (func, arg, site),
+            _ => return Err(RuntimeError::ThunkCycle),
+        };
+        thunk.replace(ir::ThunkState::Forcing {
+            func: func.clone(),
+            arg: arg.clone(),
+            site,
+        });
+        self.push_demand(ir::Demand::Update(thunk));
+        self.push_demand(ir::Demand::ApplyTo { arg, site });
+        self.state.env = ir::Env::nil().push(func);
+        Ok(())
+    }
+
+    fn push_demand(&mut self, demand: ir::Demand) {
+        let frame = ir::Frame {
+            code: std::mem::take(&mut self.state.code),
+            env: std::mem::take(&mut self.state.env),
+            call_site: None,
+            callee: None,
+            demand: Some(demand),
+        };
+        self.state.dump.push(frame);
+    }
+
+    fn lookup(
+        &self,
+        index: NonZeroUsize,
+        site: Option<SourceRange>,
+    ) -> Result<Value, RuntimeError> {
+        self.state
+            .env
+            .get(index)
+            .cloned()
+            .ok_or_else(|| RuntimeError::IndexOutOfBounds {
+                index,
+                trace: StackTrace::capture(&self.state, site),
+            })
+    }
+
+    fn call(
+        &mut self,
+        func: Value,
+        arg: Value,
+        site: Option<SourceRange>,
+    ) -> Result<Option<Suspended>, RuntimeError> {
+        debug!("call: func: {:?}, arg: {:?}", PP::new(&func), PP::new(&arg));
+        // 未評価のサンクは評価してから適用し直す。クロージャ以外は引数の値も必要になる
+        if let Value::Thunk(thunk) = &func {
+            match thunk.value() {
+                Some(func) => return self.call(func, arg, site),
+                None => {
+                    self.push_demand(ir::Demand::ApplyTo { arg, site });
+                    self.state.env = self.state.env.push(func);
+                    return Ok(None);
+                }
+            }
+        }
+        if let Value::Thunk(thunk) = &arg
+            && !matches!(func, Value::Closure { .. })
+        {
+            match thunk.value() {
+                Some(arg) => return self.call(func, arg, site),
+                None => {
+                    self.push_demand(ir::Demand::ArgOf { func, site });
+                    self.state.env = self.state.env.push(arg);
+                    return Ok(None);
+                }
+            }
+        }
+        // プリミティブは In で中断し得るので、結果が出てから通知する
+        if !matches!(func, Value::Prim(_)) {
+            for observer in &mut self.observers {
+                observer.on_app(&func, &arg, site);
+            }
+        }
         match func {
             Value::Char(expected) => {
-                let return_value = match arg {
-                    Value::Char(actual) if expected == actual => church_true(),
-                    _ => church_false(),
-                };
+                let return_value =
+                    church::boolean(matches!(arg, Value::Char(actual) if expected == actual));
                 self.state.env = self.state.env.push(return_value);
             }
-            Value::Closure { code, env } => {
+            Value::Closure { code, env, origin } => {
+                if let Some(detector) = self.loop_detector.as_mut() {
+                    detector.enter(self.steps, origin);
+                }
                 let frame = ir::Frame {
                     code: std::mem::take(&mut self.state.code),
                     env: std::mem::take(&mut self.state.env),
+                    call_site: site,
+                    callee: origin,
+                    demand: None,
                 };
                 self.state.dump.push(frame);
 
                 self.state.code = code;
                 self.state.env = env.push(arg);
             }
+            Value::Native(native) => {
+                let result_value = native.call(self, arg)?;
+                self.state.env = self.state.env.push(result_value);
+            }
+            // 捕まえた時の続きに arg を返す
+            Value::Continuation(state) => {
+                let state = Rc::unwrap_or_clone(state);
+                self.state = ir::State {
+                    code: state.code,
+                    env: state.env.push(arg),
+                    dump: state.dump,
+                };
+            }
+            Value::Thunk(_) => unreachable!(),
             Value::Prim(prim) => {
                 let result_value = match prim {
                     Prim::In => {
-                        let mut buf = [0u8; 1];
-                        match std::io::stdin().read(&mut buf) {
-                            Ok(1) => {
-                                debug!("io: stdin: byte={} {:?}", buf[0], buf[0] as char);
-                                Value::Char(buf[0])
+                        // 注入した故障は実際の入力を消費しない
+                        let fetch = match self.faults.input() {
+                            Some(fetch) => fetch,
+                            None => self.fetch()?,
+                        };
+                        if matches!(fetch, Fetch::Char(_) | Fetch::Eof) {
+                            self.faults.count_in();
+                        }
+                        match fetch {
+                            Fetch::Char(c) => {
+                                self.io_event = Some(IoEvent::In(c));
+                                Value::Char(c)
                             }
-                            _ => arg,
+                            Fetch::Eof => {
+                                self.io_event = Some(IoEvent::Eof);
+                                arg.clone()
+                            }
+                            Fetch::Pending => return Ok(Some(Suspended::NeedInput)),
+                            fetch => return Err(self.replay_error(fetch)),
                         }
                     }
                     Prim::Succ => {
-                        if let Value::Char(char) = arg {
-                            Value::Char(char.wrapping_add(1))
+                        if let Value::Char(c) = arg {
+                            Value::Char(self.char_mode.succ(c))
                         } else {
-                            return Err(RuntimeError::NotAChar(arg));
+                            return Err(self.not_a_char(arg, site));
                         }
                     }
                     Prim::Out => {
                         if let Value::Char(c) = arg {
-                            print!("{}", c as char);
-                            debug!("io: stdout: byte={} {:?}", c, c as char);
-                            arg
+                            let fails = self.faults.output_fails();
+                            let n = self.faults.count_out();
+                            if fails {
+                                return Err(RuntimeError::Io(std::io::Error::other(format!(
+                                    "injected fault on Out #{}",
+                                    n
+                                ))));
+                            }
+                            self.write_char(c)?;
+                            self.io_event = Some(IoEvent::Out(c));
+                            Value::Char(c)
+                        } else {
+                            return Err(self.not_a_char(arg, site));
+                        }
+                    }
+                    Prim::Exit => {
+                        if let Value::Char(c) = arg {
+                            // 次のステップから Status::Exited を返し続ける
+                            self.exit_code = Some(c);
+                            Value::Char(c)
+                        } else {
+                            return Err(self.not_a_char(arg, site));
+                        }
+                    }
+                    Prim::Pred => {
+                        if let Value::Char(c) = arg {
+                            Value::Char(self.char_mode.pred(c))
                         } else {
-                            return Err(RuntimeError::NotAChar(arg));
+                            return Err(self.not_a_char(arg, site));
                         }
                     }
+                    Prim::Fail => {
+                        let message = self.decode_message(&arg, site)?;
+                        return Err(RuntimeError::Failed {
+                            message,
+                            trace: StackTrace::capture(&self.state, site),
+                        });
+                    }
+                    // 引数は使わず、入力が尽きていれば真を返す
+                    Prim::Eof => {
+                        let fetch = match self.faults.input() {
+                            Some(fetch) => fetch,
+                            None => self.input.peek(self.steps)?,
+                        };
+                        match fetch {
+                            Fetch::Char(_) => church::boolean(false),
+                            Fetch::Eof => {
+                                self.io_event = Some(IoEvent::Eof);
+                                church::boolean(true)
+                            }
+                            Fetch::Pending => return Ok(Some(Suspended::NeedInput)),
+                            fetch => return Err(self.replay_error(fetch)),
+                        }
+                    }
+                    // f を新しいタスクで実行し、そのタスクとつながったチャネルを返す
+                    Prim::Spawn => {
+                        Value::Prim(Prim::Channel(self.scheduler.spawn(arg.clone(), site)))
+                    }
+                    Prim::Send => Value::Prim(Prim::Sender(self.channel(&arg, site)?)),
+                    Prim::Sender(channel) => {
+                        self.scheduler.send(channel, arg.clone());
+                        arg.clone()
+                    }
+                    Prim::Recv => match self.scheduler.recv(self.channel(&arg, site)?) {
+                        Some(value) => value,
+                        None => return Ok(Some(Suspended::Blocked)),
+                    },
+                    // f に現在の継続を渡す。CallCC の結果は f の結果か、継続に渡された値になる
+                    Prim::CallCC => {
+                        let continuation = Value::Continuation(Rc::new(self.state.clone()));
+                        for observer in &mut self.observers {
+                            observer.on_app(&Value::Prim(Prim::CallCC), &arg, site);
+                            observer.on_prim(&prim, &arg, &continuation);
+                        }
+                        return self.call(arg, continuation, site);
+                    }
+                    // 文字と同じく、同じチャネルかどうかを真偽値で返す
+                    Prim::Channel(channel) => church::boolean(
+                        matches!(arg, Value::Prim(Prim::Channel(other)) if other == channel),
+                    ),
                 };
+                if !self.observers.is_empty() {
+                    let func = Value::Prim(prim.clone());
+                    for observer in &mut self.observers {
+                        observer.on_app(&func, &arg, site);
+                        if let Some(event) = self.io_event {
+                            observer.on_io(event);
+                        }
+                        observer.on_prim(&prim, &arg, &result_value);
+                    }
+                }
                 self.state.env = self.state.env.push(result_value);
             }
         }
-        Ok(())
+        Ok(None)
+    }
+
+    fn fetch(&mut self) -> std::io::Result<Fetch> {
+        match self.char_mode {
+            CharMode::Byte => self.input.fetch(self.steps),
+            CharMode::Unicode { .. } => self.input.fetch_utf8(self.steps),
+        }
+    }
+
+    fn replay_error(&self, fetch: Fetch) -> RuntimeError {
+        match fetch {
+            Fetch::Diverged { expected } => RuntimeError::ReplayDiverged {
+                expected,
+                actual: self.steps,
+            },
+            _ => RuntimeError::ReplayExhausted,
+        }
+    }
+
+    // Fail に渡された文字、または文字のリストをメッセージにする
+    fn decode_message(
+        &mut self,
+        arg: &Value,
+        site: Option<SourceRange>,
+    ) -> Result<String, RuntimeError> {
+        let chars = match arg {
+            Value::Char(c) => Some(vec![*c]),
+            _ => church::decode_list(self, arg)?.and_then(|items| {
+                items
+                    .iter()
+                    .map(|item| match item {
+                        Value::Char(c) => Some(*c),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()
+            }),
+        };
+        let Some(chars) = chars else {
+            return Err(RuntimeError::NotAMessage {
+                value: arg.clone(),
+                trace: StackTrace::capture(&self.state, site),
+            });
+        };
+        Ok(match self.char_mode {
+            CharMode::Byte => {
+                let bytes: Vec<u8> = chars.iter().map(|&c| c as u8).collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+            CharMode::Unicode { .. } => chars
+                .iter()
+                .map(|&c| char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        })
+    }
+
+    fn write_char(&mut self, c: u32) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout();
+        let w: &mut dyn Write = match self.output.as_mut() {
+            Some(w) => w.as_mut(),
+            None => &mut stdout,
+        };
+        match self.char_mode {
+            CharMode::Byte => {
+                // 従来通り、バイトの値を U+0000..U+00FF の文字とみなして UTF-8 で書き出す
+                let ch = c as u8 as char;
+                debug!("io: stdout: byte={} {:?}", c, ch);
+                write!(w, "{}", ch)
+            }
+            CharMode::Unicode { .. } => {
+                // サロゲートなど文字にならない符号位置は U+FFFD として書き出す
+                let ch = char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER);
+                debug!("io: stdout: code point={:#x} {:?}", c, ch);
+                write!(w, "{}", ch)
+            }
+        }
+    }
+
+    fn channel(&self, value: &Value, site: Option<SourceRange>) -> Result<u32, RuntimeError> {
+        match value {
+            Value::Prim(Prim::Channel(channel)) if self.scheduler.is_channel(*channel) => {
+                Ok(*channel)
+            }
+            _ => Err(RuntimeError::NotAChannel {
+                value: value.clone(),
+                trace: StackTrace::capture(&self.state, site),
+            }),
+        }
+    }
+
+    fn not_a_char(&self, value: Value, site: Option<SourceRange>) -> RuntimeError {
+        RuntimeError::NotAChar {
+            value,
+            trace: StackTrace::capture(&self.state, site),
+        }
+    }
+}
+
+impl RuntimeError {
+    pub fn stack_trace(&self) -> Option<&StackTrace> {
+        match self {
+            RuntimeError::IndexOutOfBounds { trace, .. } => Some(trace),
+            RuntimeError::NotAChar { trace, .. } => Some(trace),
+            RuntimeError::Failed { trace, .. } => Some(trace),
+            RuntimeError::NotAMessage { trace, .. } => Some(trace),
+            RuntimeError::NotAChannel { trace, .. } => Some(trace),
+            _ => None,
+        }
+    }
+}
+
+impl CharMode {
+    // Unicode の符号位置の総数
+    pub const UNICODE_MODULUS: u32 = 0x110000;
+
+    pub fn unicode() -> Self {
+        CharMode::Unicode {
+            modulus: Self::UNICODE_MODULUS,
+        }
+    }
+
+    fn modulus(&self) -> u64 {
+        match self {
+            CharMode::Byte => 0x100,
+            CharMode::Unicode { modulus } => u64::from(*modulus).max(1),
+        }
+    }
+
+    fn succ(&self, c: u32) -> u32 {
+        ((u64::from(c) + 1) % self.modulus()) as u32
+    }
+
+    fn pred(&self, c: u32) -> u32 {
+        let modulus = self.modulus();
+        ((u64::from(c) % modulus + modulus - 1) % modulus) as u32
+    }
+}
+
+impl CompiledProgram {
+    pub fn new(prog: &Prog) -> Self {
+        Self {
+            code: Rc::new(ir::Code::from(prog)),
+            items: prog.items.len(),
+        }
+    }
+
+    // 標準入力から読む VM を作る
+    pub fn vm(&self) -> VM {
+        self.instantiate(Input::new(Source::Stdin))
+    }
+
+    // 入力が尽きると run() が Suspended::NeedInput を返して中断する VM を作る
+    pub fn resumable(&self) -> VM {
+        self.instantiate(Input::new(Source::Host))
+    }
+
+    // input を読み終えると EOF になる VM を作る
+    pub fn with_input(&self, input: &[u8]) -> VM {
+        let mut vm = self.resumable();
+        vm.feed(input);
+        vm.feed_eof();
+        vm
+    }
+
+    fn instantiate(&self, input: Input) -> VM {
+        let dump0 = vec![ir::Frame {
+            code: VecDeque::new(),
+            env: ir::Env::nil(),
+            call_site: None,
+            callee: None,
+            demand: None,
+        }];
+
+        let state = ir::State {
+            code: ir::Code::clone(&self.code),
+            env: initial_env(Dialect::Standard),
+            dump: dump0,
+        };
+
+        debug!("init: {:?}", PP::new(&state));
+
+        let mut vm = VM::from_state(state, input, 0);
+        vm.items = self.items;
+        vm
+    }
+}
+
+impl InterruptHandle {
+    // 実行中の run() を次のステップの手前で止める
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    // 実行していない間に届いた中断要求を捨てる
+    pub fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    // 中断要求を消費する。中断後にもう一度 run() すれば続きから再開できる
+    fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::Relaxed)
     }
 }
 
 // ========================================================================== //
 
-fn identity() -> ir::Value {
-    ir::Value::Closure {
-        code: VecDeque::new(),
-        env: ir::Env::nil(),
+fn waiting_tasks(tasks: &[BlockedTask]) -> String {
+    let tasks: Vec<_> = tasks
+        .iter()
+        .map(|task| match (task.id, task.site) {
+            (0, _) => format!("main on channel {}", task.channel),
+            (id, Some(site)) => format!(
+                "task {} spawned at {} on channel {}",
+                id, site, task.channel
+            ),
+            (id, None) => format!("task {} on channel {}", id, task.channel),
+        })
+        .collect();
+    if tasks.is_empty() {
+        String::new()
+    } else {
+        format!(": {}", tasks.join(", "))
     }
 }
 
-fn church_false() -> ir::Value {
-    let code = VecDeque::from(vec![ir::Instr::Abs {
-        arity: NonZeroUsize::new(1).unwrap(),
-        body: VecDeque::new(),
-    }]);
-    ir::Value::Closure {
-        code,
-        env: ir::Env::nil(),
+fn in_functions(functions: &[SourceRange]) -> String {
+    let functions: Vec<_> = functions.iter().map(|f| format!("fn@{}", f)).collect();
+    if functions.is_empty() {
+        String::new()
+    } else {
+        format!(" in {}", functions.join(", "))
     }
 }
 
-fn church_true() -> ir::Value {
-    let code = VecDeque::from(vec![ir::Instr::Abs {
-        arity: NonZeroUsize::new(1).unwrap(),
-        body: VecDeque::from(vec![ir::Instr::App {
-            func_idx: NonZeroUsize::new(3).unwrap(),
-            arg_idx: NonZeroUsize::new(2).unwrap(),
-        }]),
-    }]);
-    ir::Value::Closure {
-        code,
-        env: ir::Env::nil().push(identity()),
+// 評価済みのサンクは値に置き換える。未評価ならそのサンクを返す
+fn resolve(value: Value) -> Result<Value, ir::Thunk> {
+    match value {
+        Value::Thunk(thunk) => thunk.value().ok_or(thunk),
+        value => Ok(value),
+    }
+}
+
+// 拡張方言のプリミティブは In より奥に置き、仕様通りのプログラムからは見えないようにする
+fn initial_env(dialect: Dialect) -> Rc<ir::Env> {
+    let mut env = ir::Env::nil();
+    match dialect {
+        Dialect::Standard => {}
+        Dialect::Extended => {
+            env = env
+                .push(Value::Prim(Prim::Eof))
+                .push(Value::Prim(Prim::Fail))
+                .push(Value::Prim(Prim::Pred))
+                .push(Value::Prim(Prim::Exit));
+        }
+        Dialect::Concurrent => {
+            env = env
+                .push(Value::Prim(Prim::Recv))
+                .push(Value::Prim(Prim::Send))
+                .push(Value::Prim(Prim::Spawn));
+        }
+        Dialect::Continuation => {
+            env = env.push(Value::Prim(Prim::CallCC));
+        }
+    }
+    env.push(Value::Prim(Prim::In))
+        .push(Value::Char(b'w'.into()))
+        .push(Value::Prim(Prim::Succ))
+        .push(Value::Prim(Prim::Out))
+}
+
+// ========================================================================== //
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flight_recorder::Rule;
+    use crate::io::{Event, SharedBuffer};
+    use crate::parser::parse_prog;
+    use std::cell::RefCell;
+
+    fn result(vm: &VM) -> Option<u8> {
+        match vm.state.env.get(NonZeroUsize::new(1).unwrap()) {
+            Some(Value::Char(c)) => u8::try_from(*c).ok(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_suspend_on_input() {
+        // λx. In x
+        let prog = parse_prog("wWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+
+        vm.feed(b"a");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'a'));
+    }
+
+    #[test]
+    fn test_resume_after_eof() {
+        // λx. In (In x)
+        let prog = parse_prog("wWWWWWwWWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+
+        vm.feed(b"a");
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+
+        vm.feed_eof();
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'a'));
+    }
+
+    #[test]
+    fn test_unicode() {
+        // λx. Succ (In x)
+        let prog = parse_prog("wWWWWWwWWWWw").unwrap();
+        let top = |vm: &VM| match vm.state.env.get(NonZeroUsize::new(1).unwrap()) {
+            Some(Value::Char(c)) => Some(*c),
+            _ => None,
+        };
+
+        // 1文字の途中までしか届いていなければ続きを待つ
+        let mut vm = VM::resumable(&prog);
+        vm.set_char_mode(CharMode::unicode());
+        vm.feed(&"あ".as_bytes()[..2]);
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        vm.feed(&"あ".as_bytes()[2..]);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(top(&vm), Some(u32::from('ぃ')));
+
+        let mut vm = VM::resumable(&prog);
+        vm.set_char_mode(CharMode::Unicode { modulus: 0x3043 });
+        vm.feed("あ".as_bytes());
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(top(&vm), Some(0));
+
+        // 不正な並びは U+FFFD として読み、次の文字の先頭は読み戻す
+        let mut vm = VM::resumable(&prog);
+        vm.set_char_mode(CharMode::unicode());
+        vm.feed(b"\xe3a");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(top(&vm), Some(0xfffe));
+
+        // バイトモードでは 1 バイトずつ読み、256 で一周する
+        let mut vm = VM::resumable(&prog);
+        vm.feed(&[0xff]);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(top(&vm), Some(0));
+
+        // バイトモードの Out は 0x80 以上のバイトを U+0080..U+00FF として書き出す
+        // λx. Out (In x)
+        let prog = parse_prog("wWWWWWwWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        let output = SharedBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        for byte in [b'a', 0xe9] {
+            vm.reset();
+            vm.feed(&[byte]);
+            assert_eq!(vm.run().unwrap(), Status::Halted);
+        }
+        assert_eq!(output.contents(), "aé".as_bytes());
+    }
+
+    #[test]
+    fn test_extended() {
+        // λx. Exit (Pred w)
+        let prog = parse_prog("wWWWWWWWwwwwWWWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Extended);
+        assert_eq!(vm.run().unwrap(), Status::Exited(b'v'.into()));
+        assert_eq!(vm.run().unwrap(), Status::Exited(b'v'.into()));
+
+        // 標準の方言では In より奥には何も無い
+        let mut vm = VM::resumable(&prog);
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::IndexOutOfBounds { .. })
+        ));
+
+        // is_eof = λx. Eof x, fail = λx. Fail x
+        let prog = parse_prog("wWWWWWWWWWw v wWWWWWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Extended);
+        let defs = vm.definitions().unwrap();
+        let w = Value::Char(b'w'.into());
+
+        assert!(matches!(
+            vm.apply(defs[0].clone(), w.clone()),
+            Err(RuntimeError::Suspended(Suspended::NeedInput))
+        ));
+        vm.feed(b"a");
+        let is_eof = vm.apply(defs[0].clone(), w.clone()).unwrap();
+        assert_eq!(church::decode_bool(&mut vm, &is_eof).unwrap(), Some(false));
+        vm.feed_eof();
+        let is_eof = vm.apply(defs[0].clone(), w.clone()).unwrap();
+        assert_eq!(church::decode_bool(&mut vm, &is_eof).unwrap(), Some(false));
+
+        match vm.apply(defs[1].clone(), church::bytes(b"oops")) {
+            Err(RuntimeError::Failed { message, .. }) => assert_eq!(message, "oops"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(matches!(
+            vm.apply(defs[1].clone(), church::numeral(2)),
+            Err(RuntimeError::NotAMessage { .. })
+        ));
+
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Extended);
+        let defs = vm.definitions().unwrap();
+        vm.feed_eof();
+        let is_eof = vm.apply(defs[0].clone(), w).unwrap();
+        assert_eq!(church::decode_bool(&mut vm, &is_eof).unwrap(), Some(true));
+    }
+
+    #[test]
+    fn test_call_by_need() {
+        // λx. (x x) を捨てて Out w を返す。値渡しでは main が自分を呼び続ける
+        let prog = parse_prog("wWwWWWwwwww").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_step_limit(Some(1000));
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::StepLimitExceeded(1000))
+        ));
+
+        let mut vm = VM::resumable(&prog);
+        vm.set_strategy(Strategy::CallByNeed);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'w'));
+
+        // λx. Succ (In x) は値が必要になった時に In で中断し、再開できる
+        let prog = parse_prog("wWWWWWwWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_strategy(Strategy::CallByNeed);
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        vm.feed(b"a");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'b'));
+
+        // λx. let c = In x in c c の c は共有され、In は一度しか読まない
+        let prog = parse_prog("wWWWWWwWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_strategy(Strategy::CallByNeed);
+        let defs = vm.definitions().unwrap();
+        vm.feed(b"ab");
+        let same = vm.apply(defs[0].clone(), Value::Char(b'w'.into())).unwrap();
+        assert_eq!(church::decode_bool(&mut vm, &same).unwrap(), Some(true));
+    }
+
+    #[test]
+    fn test_failed_thunk() {
+        let prog = parse_prog("w").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_strategy(Strategy::CallByNeed);
+        vm.set_output(Box::new(std::io::sink()));
+        // Out が引数を評価すると Fail で失敗する。2回目も循環ではなく同じエラーになる
+        let thunk = ir::Thunk::new(Value::Prim(Prim::Fail), Value::Char(b'x'.into()), None);
+        for _ in 0..2 {
+            match vm.apply(Value::Prim(Prim::Out), Value::Thunk(thunk.clone())) {
+                Err(RuntimeError::Failed { message, .. }) => assert_eq!(message, "x"),
+                result => panic!("unexpected result: {:?}", result),
+            }
+            assert!(matches!(thunk.state(), ir::ThunkState::Delayed { .. }));
+        }
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        // main = λx. x は自分自身を返し続ける
+        let prog = parse_prog("w").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_loop_detection(true);
+        match vm.run() {
+            Err(RuntimeError::InfiniteLoop { functions, .. }) => {
+                let functions: Vec<_> = functions.iter().map(|f| f.to_string()).collect();
+                assert_eq!(functions, vec!["1:1"]);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // 同じ関数を同じ引数で2回呼んでもループではない
+        // id = λy. y, main = λx. Out (id w) を id w の後で評価する
+        let prog = parse_prog("w v wWWwwwwwWWWwwwwwwWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_loop_detection(true);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'w'));
+
+        // λx. In x は EOF の度に入出力があるので止めない
+        let prog = parse_prog("wWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_loop_detection(true);
+        vm.set_step_limit(Some(1000));
+        vm.feed_eof();
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::StepLimitExceeded(1000))
+        ));
+    }
+
+    #[test]
+    fn test_concurrent() {
+        // child = λc. Send c w, main = λx. Recv (Spawn child)
+        let prog = parse_prog("wWWWWWWWwWwwwww v wWWWWWWWwwWWWWWWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Concurrent);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'w'));
+
+        // child = λc. Recv c。main と child が互いを待ち続ける
+        let prog = parse_prog("wWWWWWWWWw v wWWWWWWWwwWWWWWWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Concurrent);
+        let Err(RuntimeError::Deadlock { tasks }) = vm.run() else {
+            panic!("expected a deadlock");
+        };
+        let waiting: Vec<_> = tasks.iter().map(|task| (task.id, task.channel)).collect();
+        assert_eq!(waiting, vec![(0, 0), (1, 1)]);
+        assert!(vm.save_snapshot(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_call_cc() {
+        // f = λk. Succ (k w), main = λx. CallCC f。k で抜けるので Succ は適用されない
+        let prog = parse_prog("wWwwwwWWWWw v wWWWWWWWww").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Continuation);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'w'));
+    }
+
+    #[test]
+    fn test_generator() {
+        // pair = λa b s. s a b
+        // yielder = λret v resume. ret (pair resume v)
+        // yield = λret v. CallCC (yielder ret v)。再開されると次の ret を返す
+        // gen = λret. w と Succ w を順に yield し、最後に受け取った ret に w を渡す
+        // step = λk v. Out v; CallCC k
+        // main = λx. (CallCC gen) step step
+        let prog = parse_prog(concat!(
+            "wwwWwwwWwww v wwwWWWWwWwwwWWWWWw v wwWWWwwWwwWWWWWWWWWWWw v ",
+            "wWWwWwwwwwwwwWWWWWWWWwwwwwwwwwWWWWWwwWwwWwwwwwwwwwwww v ",
+            "wwWWWWWWWwWWWWWWWWWWWWwww v wWWWWWWWWWWWwwwWwwwWwwww",
+        ))
+        .unwrap();
+        let output = SharedBuffer::default();
+        let mut vm = VM::resumable(&prog);
+        vm.set_dialect(Dialect::Continuation);
+        vm.set_output(Box::new(output.clone()));
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"wx");
+        assert_eq!(result(&vm), Some(b'w'));
+    }
+
+    #[test]
+    fn test_fault_plan() {
+        use crate::fault::Fault;
+
+        // λx. In (In x)
+        let prog = parse_prog("wWWWWWwWWWWWWw").unwrap();
+        for (fault, expected) in [(Fault::Eof(2), b'a'), (Fault::Byte(2, b'z'), b'z')] {
+            let mut vm = VM::resumable(&prog);
+            vm.set_fault_plan(FaultPlan::new([fault]));
+            vm.feed(b"ab");
+            assert_eq!(vm.run().unwrap(), Status::Halted);
+            assert_eq!(result(&vm), Some(expected));
+        }
+
+        // λx. Out (In x)
+        let prog = parse_prog("wWWWWWwWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_fault_plan(FaultPlan::new([Fault::OutError(1)]));
+        vm.feed(b"a");
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.to_string(), "I/O error: injected fault on Out #1");
+    }
+
+    #[test]
+    fn test_replay() {
+        // λx. In (In x)
+        let prog = parse_prog("wWWWWWwWWWWWWw").unwrap();
+
+        let replay = Replay::read(&b"byte 97\neof 4\n"[..]).unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.replay_input(replay);
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'a'));
+
+        let replay = Replay::read(&b"byte 97\n"[..]).unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.replay_input(replay);
+        assert!(matches!(vm.run(), Err(RuntimeError::ReplayExhausted)));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // λx. In x
+        let prog = parse_prog("wWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.feed(b"a");
+
+        let handle = vm.interrupt_handle();
+        std::thread::spawn(move || handle.cancel()).join().unwrap();
+        assert!(matches!(vm.run(), Err(RuntimeError::Interrupted)));
+        assert_eq!(vm.steps(), 0);
+
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(result(&vm), Some(b'a'));
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        // f = λx. Succ x, g = λx. f x, main = λx. g x
+        let prog = parse_prog("wWWWw\nvwWWw\nvwWWw\n").unwrap();
+        let mut vm = VM::new(&prog);
+
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, RuntimeError::NotAChar { .. }));
+        let trace = err.stack_trace().unwrap();
+        assert_eq!(
+            trace.to_string(),
+            [
+                "   0: fn@1:1 at 1:2",
+                "   1: fn@2:2 at 2:3",
+                "   2: <main> at 3:3"
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_flight_recorder() {
+        // λx. In x
+        let prog = parse_prog("wWWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        vm.set_flight_recorder(2);
+
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        vm.feed(b"a");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+
+        let transitions: Vec<_> = vm.flight_recorder().transitions().collect();
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].rule, Rule::SelfApply);
+        assert_eq!(transitions[1].step, 3);
+        assert_eq!(transitions[1].io, Some(IoEvent::In(b'a'.into())));
+        assert_eq!(transitions[1].env_depth(), 5);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // λx. x (自分自身を返し続ける)
+        let prog = parse_prog("w").unwrap();
+        let mut vm = VM::new(&prog);
+        vm.set_step_limit(Some(100));
+
+        assert!(matches!(
+            vm.run(),
+            Err(RuntimeError::StepLimitExceeded(100))
+        ));
+        assert_eq!(vm.steps(), 100);
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        apps: usize,
+        abss: usize,
+        returns: usize,
+        prims: Vec<(u32, u32)>,
+        io: Vec<IoEvent>,
+    }
+
+    impl Observer for Counter {
+        fn on_app(&mut self, _func: &Value, _arg: &Value, _site: Option<SourceRange>) {
+            self.apps += 1;
+        }
+
+        fn on_abs(&mut self, _arity: NonZeroUsize, _closure: &Value) {
+            self.abss += 1;
+        }
+
+        fn on_return(&mut self, _value: &Value) {
+            self.returns += 1;
+        }
+
+        fn on_prim(&mut self, _prim: &Prim, arg: &Value, result: &Value) {
+            if let (Value::Char(a), Value::Char(r)) = (arg, result) {
+                self.prims.push((*a, *r));
+            }
+        }
+
+        fn on_io(&mut self, event: IoEvent) {
+            self.io.push(event);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        // λx. Succ (In x)
+        let prog = parse_prog("wWWWWWwWWWWw").unwrap();
+        let mut vm = VM::resumable(&prog);
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        vm.add_observer(Box::new(counter.clone()));
+
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        vm.feed(b"a");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+
+        let counter = counter.borrow();
+        assert_eq!(counter.apps, 3);
+        assert_eq!(counter.abss, 1);
+        assert_eq!(counter.returns, 1);
+        assert_eq!(counter.prims, vec![(b'a'.into(), b'b'.into())]);
+        assert_eq!(counter.io, vec![IoEvent::In(b'a'.into())]);
+    }
+
+    #[test]
+    fn test_compiled_program() {
+        // λx. Out (Succ (In x))
+        let prog = parse_prog("wWWWWWwWWWWwWWWWw").unwrap();
+        let compiled = CompiledProgram::new(&prog);
+
+        for (input, expected) in [(&b"a"[..], &b"b"[..]), (b"x", b"y")] {
+            let mut vm = compiled.with_input(input);
+            let output = SharedBuffer::default();
+            vm.set_output(Box::new(output.clone()));
+            assert_eq!(vm.run().unwrap(), Status::Halted);
+            assert_eq!(output.contents(), expected);
+        }
+
+        // 入力と出力先以外は作り直さずに使い回す
+        let mut vm = compiled.resumable();
+        let output = SharedBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.feed(b"ab");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        vm.reset();
+        assert_eq!(vm.steps(), 0);
+        assert_eq!(vm.run().unwrap(), Status::Suspended(Suspended::NeedInput));
+        vm.feed(b"c");
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"bd");
+
+        // 再生するセッションも先頭から読み直す
+        let mut vm = compiled.resumable();
+        let output = SharedBuffer::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.replay_input(Replay::new([Event::Byte(b'a')]));
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        vm.reset();
+        assert_eq!(vm.run().unwrap(), Status::Halted);
+        assert_eq!(output.contents(), b"bb");
+
+        // Abs の本体は複製せずに共有する
+        let body = |vm: &VM| match vm.state().code.front() {
+            Some(ir::Instr::Abs { body, .. }) => body.clone(),
+            _ => panic!("expected an Abs"),
+        };
+        assert!(Rc::ptr_eq(&body(&compiled.vm()), &body(&compiled.vm())));
+    }
+
+    #[test]
+    fn test_apply() {
+        // succ = λx. Succ x, call_w = λh. h w
+        let prog = parse_prog("wWWWw v wWwwwww").unwrap();
+        let mut vm = VM::resumable(&prog);
+        let defs = vm.definitions().unwrap();
+        assert_eq!(defs.len(), 2);
+
+        let result = vm.apply(defs[0].clone(), Value::Char(b'a'.into())).unwrap();
+        assert!(matches!(result, Value::Char(c) if c == u32::from(b'b')));
+
+        // Grass から呼ばれた Rust の関数が、さらに Grass の関数を呼ぶ
+        let succ = defs[0].clone();
+        let callback = Value::Native(ir::Native::new(move |vm, arg| vm.apply(succ.clone(), arg)));
+        let result = vm.apply(defs[1].clone(), callback).unwrap();
+        assert!(matches!(result, Value::Char(c) if c == u32::from(b'x')));
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        // λx. Succ (Succ w)
+        let prog = parse_prog("wWWWwwwwWWWWw").unwrap();
+        let mut vm = VM::new(&prog);
+        assert_eq!(
+            vm.run_for(3).unwrap(),
+            Status::Suspended(Suspended::OutOfSteps)
+        );
+
+        let mut buf = Vec::new();
+        vm.save_snapshot(&mut buf).unwrap();
+        let mut restored = VM::load_snapshot(&mut buf.as_slice()).unwrap();
+        assert_eq!(restored.steps(), 3);
+
+        let mut buf2 = Vec::new();
+        restored.save_snapshot(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+
+        assert_eq!(restored.run().unwrap(), Status::Halted);
+        assert_eq!(result(&restored), Some(b'y'));
     }
 }